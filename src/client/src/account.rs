@@ -1,21 +1,46 @@
 use std::error::Error;
+use crate::file::AccountLock;
 use crate::key::{AccountKeys, IdentityKeyPair, SignedPreKeyPair};
+use crate::socket::KeyUpdate;
 
 #[derive(Clone, Debug)]
 pub struct Account {
     account: String,
     key: AccountKeys,
+    recovery: Option<String>,
 }
 
 impl Account {
-    pub async fn new(account: String) -> Result<Self, Box<dyn Error>> {
-        let key = AccountKeys::new(&account).await?;
-        Ok(Self { account, key })
+    /// Registers `account` with `server` under fresh keys.
+    pub async fn new(server: &str, account: String) -> Result<Self, Box<dyn Error>> {
+        let (key, recovery) = AccountKeys::new(server, &account, KeyUpdate::Register, None).await?;
+        Ok(Self { account, key, recovery: Some(recovery) })
+    }
+    
+    /// Replaces the registered keys of an existing account. The old identity key signs the change
+    /// when it is still available locally, otherwise the recovery code has to be presented. The
+    /// account is locked meanwhile, so no other process works with the keys being replaced.
+    pub async fn reset(server: &str, account: String, recovery_code: &str) -> Result<Self, Box<dyn Error>> {
+        let _lock = AccountLock::acquire(&account)?;
+        let previous = AccountKeys::load(&account).ok();
+        let update = match &previous {
+            Some(keys) => KeyUpdate::Signed(&keys.identity_keypair),
+            None => KeyUpdate::Recovery(recovery_code),
+        };
+        
+        let storage_key = previous.as_ref().map(|keys| keys.storage_key);
+        let (key, recovery) = AccountKeys::new(server, &account, update, storage_key).await?;
+        Ok(Self { account, key, recovery: Some(recovery) })
     }
     
     pub fn load(account: String) -> Result<Self, Box<dyn Error>> {
         let key = AccountKeys::load(&account)?;
-        Ok(Self { account, key })
+        Ok(Self { account, key, recovery: None })
+    }
+    
+    /// The recovery code of keys registered in this run. It is never written to disk.
+    pub fn recovery_code(&self) -> Option<&str> {
+        self.recovery.as_deref()
     }
     
     pub fn name(&self) -> &str {
@@ -30,6 +55,10 @@ impl Account {
         &self.key.signed_prekey
     }
     
+    pub fn storage_key(&self) -> [u8; 32] {
+        self.key.storage_key
+    }
+    
     pub fn find_opk(&self, id: i32) -> Option<[u8; 32]> {
         self.key.one_time_prekeys.iter()
             .find(|k| k.id == id)
@@ -1,54 +1,174 @@
 use eframe::egui;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
 use std::sync::Arc;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 use std::sync::Mutex;
+use chrono::Local;
 use log::{info, warn};
 use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use crate::account::Account;
-use crate::file::{init_load, init_load_user, SessionKey};
-use crate::message::Message;
+use crate::chat::{receive_message, write_message};
+use crate::file::{init_load, init_load_user, MessageHistory, SessionKey};
+use crate::index::{SearchHit, SearchIndex};
+use crate::message::{format_age, format_timestamp, Message};
+use crate::push::{self, PushEvent};
 use crate::session::Session;
-use crate::socket::{get_session, get_session_list, search, MessagePayload, RequestPayload};
+use crate::socket::{
+    ack, block_user, get_block_list, get_key_changes, get_outgoing_list, get_session, get_session_list, 
+    refresh_tree_head, search, set_discoverable, sign_in, unblock_user, Connection,
+    KeyChangePayload, MessagePayload, PendingRequest, RequestPayload
+};
+use crate::transcript::{ExportFormat, Transcript};
+use crate::util::{HISTORY_PAGE_SIZE, POLL_INTERVAL};
 
 
 pub struct AppState {
     input_text: String,
     current_page: Page,
     account: Arc<Mutex<Option<Account>>>,
+    connection: Arc<Mutex<Option<Connection>>>,
     target: Arc<Mutex<Option<Session>>>,
     message: Arc<Mutex<Vec<Message>>>,
+    history_page: usize,
+    history_top: bool,
+    query_text: String,
+    query_peer: Option<String>,
+    query_results: Vec<SearchHit>,
+    jump_to: Option<u64>,
+    scroll_pending: bool,
+    export_peer: Option<String>,
+    export_path: String,
+    passphrase: String,
+    transfer_status: Arc<Mutex<String>>,
+    reload_users: Arc<AtomicBool>,
     backup_user: Vec<String>,
     pub search_results: Arc<Mutex<Vec<String>>>,
+    prefix_search: bool,
+    discoverable: Arc<Mutex<Option<bool>>>,
     load_user: Vec<String>,
-    request_user: Arc<Mutex<Vec<String>>>,
+    request_user: Arc<Mutex<Vec<PendingRequest>>>,
+    outgoing_requests: Arc<Mutex<Vec<PendingRequest>>>,
+    blocked_user: Arc<Mutex<Vec<String>>>,
+    block_input: String,
+    decline_notify: bool,
+    declined_by: Vec<String>,
+    key_changes: Arc<Mutex<Vec<KeyChangePayload>>>,
+    log_warning: Arc<Mutex<Option<String>>>,
+    recovery_input: String,
     runtime: Arc<Runtime>,
     refresh_task: Option<tokio::task::JoinHandle<()>>,
     should_run: Arc<AtomicBool>,
+    push_task: Option<tokio::task::JoinHandle<()>>,
+    push_events: Option<UnboundedReceiver<PushEvent>>,
+    push_connected: Arc<AtomicBool>,
+    receipts: HashMap<String, i64>,
+}
+
+/// Makes `account` the signed in account and opens its connection to the server. Fails if
+/// another process, like the daemon of the account, is using it.
+fn sign_in_as(account: Account, signed_in: &Mutex<Option<Account>>, connection: &Mutex<Option<Connection>>) -> Result<(), Box<dyn Error>> {
+    connection.lock().unwrap().replace(sign_in(&account)?);
+    signed_in.lock().unwrap().replace(account);
+    Ok(())
+}
+
+/// Forgets the handshake of the session with `target` once the server holds the request, in the
+/// open session if it is still that one and in the stored one otherwise.
+fn confirm_request(current: &Arc<Mutex<Option<Session>>>, account: &Account, target: &str) {
+    let mut current = current.lock().unwrap();
+    let result = match current.as_mut() {
+        Some(session) if session.name() == target => session.confirm_request(account.name()),
+        _ => SessionKey::load(target, Arc::new(Mutex::new(Some(account.clone()))))
+            .and_then(|mut session| session.confirm_request(account.name())),
+    };
+    
+    if let Err(e) = result {
+        warn!("Error saving the session with {}: {:?}", target, e);
+    }
+}
+
+/// Accepts the requests from peers this account has started a session with as well and not
+/// heard back from, so both sides settle on one session without waiting for a click. Returns the
+/// requests left for the user.
+async fn accept_crossing(
+    connection: &Connection,
+    requests: Vec<PendingRequest>,
+    account: Arc<Mutex<Option<Account>>>,
+    target: Arc<Mutex<Option<Session>>>,
+) -> Vec<PendingRequest> {
+    let mut remaining = vec![];
+    for request in requests {
+        let crossing = SessionKey::load(&request.account, account.clone()).is_ok_and(|session| session.awaiting_reply());
+        if !crossing {
+            remaining.push(request);
+            continue;
+        }
+        
+        match RequestPayload::receive(connection, request.account.clone(), account.clone()).await {
+            Ok((session, _)) => {
+                info!("Accepted the request {} made while ours was pending", request.account);
+                let mut target = target.lock().unwrap();
+                if target.as_ref().is_some_and(|current| current.name() == request.account) {
+                    target.replace(session);
+                }
+            },
+            Err(e) => {
+                warn!("Error receiving request: {:?}", e);
+                remaining.push(request);
+            }
+        }
+    }
+    remaining
 }
 
 impl AppState {
+    /// Runs `task` with the connection of the signed in account, if there is one.
+    fn spawn_connected<F, T>(&self, task: F)
+    where
+        F: FnOnce(Connection) -> T,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        let connection = self.connection.lock().unwrap().clone();
+        if let Some(connection) = connection {
+            self.runtime.spawn(task(connection));
+        }
+    }
+    
     fn send_message(&mut self) {
         if !self.input_text.trim().is_empty() {
-            let input_text = self.input_text.clone();
-            let message = Message::new(input_text.to_string());
-            let time = message.timestamp;
-            self.message.lock().unwrap().push(message.clone());
-            
             let account = {
-                self.account.lock().unwrap().as_ref().unwrap().name().to_string()
+                self.account.lock().unwrap().as_ref().unwrap().clone()
             };
             
-            let payload = self.target.lock().unwrap().as_mut().unwrap().add_message(message, &account);
+            let (target, outgoing) = {
+                let mut session = self.target.lock().unwrap();
+                let session = session.as_mut().unwrap();
+                (session.name().to_string(), write_message(&account, session, self.input_text.clone()))
+            };
             
-            match payload {
-                Ok(payload) => {
-                    let target = {
-                        self.target.lock().unwrap().as_ref().unwrap().name().to_string()
-                    };
-
-                    self.runtime.spawn(async move {
-                        match MessagePayload::send(&account, &target, payload, time).await {
-                            Ok(_) => { info!("Sent message"); },
+            match outgoing {
+                Ok(outgoing) => {
+                    self.message.lock().unwrap().push(outgoing.message.clone());
+                    let session = Arc::clone(&self.target);
+                    let messages = Arc::clone(&self.message);
+                    
+                    self.spawn_connected(move |connection| async move {
+                        let confirm = || confirm_request(&session, &account, &target);
+                        match outgoing.send(&connection, &account, &target, confirm).await {
+                            Ok(sent) => {
+                                info!("Sent message");
+                                // The chat may show another conversation by now.
+                                let open = session.lock().unwrap().as_ref().is_some_and(|session| session.name() == target);
+                                if open {
+                                    if let Some(message) = messages.lock().unwrap().iter_mut().find(|message| message.sender && message.id == sent.id) {
+                                        message.pending = false;
+                                    }
+                                }
+                            },
                             Err(e) => { warn!("Error sending message: {:?}", e); }
                         }
                     });
@@ -60,20 +180,300 @@ impl AppState {
         }
     }
     
+    fn refresh_requests(&self) {
+        let request_user = Arc::clone(&self.request_user);
+        let outgoing_requests = Arc::clone(&self.outgoing_requests);
+        let blocked_user = Arc::clone(&self.blocked_user);
+        let key_changes = Arc::clone(&self.key_changes);
+        let log_warning = Arc::clone(&self.log_warning);
+        let discoverable = Arc::clone(&self.discoverable);
+        let target = Arc::clone(&self.target);
+        let signed_in = self.account.clone();
+        
+        self.spawn_connected(move |connection| async move {
+            let users = match get_session_list(&connection).await {
+                Ok(users) => Some(users),
+                Err(e) => {
+                    warn!("Error getting session list: {:?}", e);
+                    None
+                }
+            };
+            if let Some(users) = users {
+                let users = accept_crossing(&connection, users, signed_in, target).await;
+                *request_user.lock().unwrap() = users;
+            }
+            
+            match get_outgoing_list(&connection).await {
+                Ok(requests) => {
+                    *outgoing_requests.lock().unwrap() = requests;
+                },
+                Err(e) => {
+                    warn!("Error getting outgoing requests: {:?}", e);
+                }
+            }
+            
+            match get_block_list(&connection).await {
+                Ok(users) => {
+                    *blocked_user.lock().unwrap() = users;
+                },
+                Err(e) => {
+                    warn!("Error getting block list: {:?}", e);
+                }
+            }
+            
+            match get_key_changes(&connection).await {
+                Ok(changes) => {
+                    key_changes.lock().unwrap().extend(changes);
+                },
+                Err(e) => {
+                    warn!("Error getting key changes: {:?}", e);
+                }
+            }
+            
+            match set_discoverable(&connection, None).await {
+                Ok(value) => {
+                    *discoverable.lock().unwrap() = Some(value);
+                },
+                Err(e) => {
+                    warn!("Error getting discovery setting: {:?}", e);
+                }
+            }
+            
+            if let Err(e) = refresh_tree_head(&connection).await {
+                warn!("Key transparency check failed: {:?}", e);
+                *log_warning.lock().unwrap() = Some(e.to_string());
+            }
+        });
+    }
+    
+    /// Opens the push connection of the signed in account if it is not running yet.
+    fn start_push(&mut self) {
+        if self.push_task.is_some() {
+            return;
+        }
+        let connection = match self.connection.lock().unwrap().clone() {
+            Some(connection) => connection,
+            None => return,
+        };
+        
+        let (sender, receiver) = unbounded_channel();
+        self.push_events = Some(receiver);
+        self.push_task = Some(self.runtime.spawn(push::run(connection, sender, Arc::clone(&self.push_connected))));
+    }
+    
+    fn stop_push(&mut self) {
+        if let Some(task) = self.push_task.take() {
+            task.abort();
+        }
+        self.push_events = None;
+        self.push_connected.store(false, std::sync::atomic::Ordering::Relaxed);
+        self.receipts.clear();
+    }
+    
+    fn handle_push_events(&mut self) {
+        let mut events = vec![];
+        if let Some(receiver) = self.push_events.as_mut() {
+            while let Ok(event) = receiver.try_recv() {
+                events.push(event);
+            }
+        }
+        
+        for event in events {
+            match event {
+                PushEvent::Message(payload) => self.handle_pushed_message(payload),
+                PushEvent::Request { account, timestamp } => {
+                    let request_user = Arc::clone(&self.request_user);
+                    let signed_in = self.account.clone();
+                    let target = Arc::clone(&self.target);
+                    self.spawn_connected(move |connection| async move {
+                        let requests = vec![PendingRequest { account, timestamp }];
+                        for request in accept_crossing(&connection, requests, signed_in, target).await {
+                            let mut request_user = request_user.lock().unwrap();
+                            if !request_user.iter().any(|pending| pending.account == request.account) {
+                                request_user.push(request);
+                            }
+                        }
+                    });
+                },
+                PushEvent::Cancelled { account } => {
+                    info!("{} cancelled the session request", account);
+                    self.request_user.lock().unwrap().retain(|request| request.account != account);
+                },
+                PushEvent::Declined { account } => {
+                    info!("{} declined the session request", account);
+                    self.outgoing_requests.lock().unwrap().retain(|request| request.account != account);
+                    if !self.declined_by.contains(&account) {
+                        self.declined_by.push(account);
+                    }
+                },
+                PushEvent::Receipt { account, count } => {
+                    info!("{} received {} messages", account, count);
+                    self.receipts.insert(account, Local::now().timestamp());
+                },
+            }
+        }
+    }
+    
+    /// Messages for the open chat go through its session, anything else through the stored session
+    /// of the sender, so nothing pushed for another conversation is lost. Only stored messages are
+    /// acknowledged, the rest is pushed again once the lease expires.
+    fn handle_pushed_message(&mut self, payload: MessagePayload) {
+        let account = match self.account.lock().unwrap().clone() {
+            Some(account) => account,
+            None => return,
+        };
+        let sender = payload.sender().to_string();
+        let id = payload.id;
+        
+        let mut target = self.target.lock().unwrap();
+        match target.as_mut() {
+            Some(session) if session.name() == sender => {
+                match receive_message(&account, session, payload) {
+                    Ok(message) => {
+                        self.message.lock().unwrap().extend(message);
+                        self.ack_message(id);
+                    },
+                    Err(e) => { warn!("Error reviving message: {:?}", e); }
+                }
+            },
+            _ => {
+                drop(target);
+                let result = SessionKey::load(&sender, self.account.clone())
+                    .and_then(|mut session| receive_message(&account, &mut session, payload));
+                
+                match result {
+                    Ok(_) => {
+                        info!("Stored message from {}", sender);
+                        self.ack_message(id);
+                        if !self.load_user.contains(&sender) {
+                            self.load_user.push(sender);
+                        }
+                    },
+                    Err(e) => { warn!("Error reviving message from {}: {:?}", sender, e); }
+                }
+            }
+        }
+    }
+    
+    fn ack_message(&self, id: i32) {
+        self.spawn_connected(move |connection| async move {
+            if let Err(e) = ack(&connection, &[id], &[]).await {
+                warn!("Error acknowledging message: {:?}", e);
+            }
+        });
+    }
+    
+    fn load_older_messages(&mut self) {
+        let account = match self.account.lock().unwrap().clone() {
+            Some(account) => account,
+            None => return,
+        };
+        let target = match self.target.lock().unwrap().as_ref() {
+            Some(target) => target.name().to_string(),
+            None => return,
+        };
+        
+        match MessageHistory::load_page(&account, &target, self.history_page - 1) {
+            Ok(mut older) => {
+                let mut messages = self.message.lock().unwrap();
+                older.extend(messages.drain(..));
+                *messages = older;
+                self.history_page -= 1;
+            },
+            Err(e) => {
+                warn!("Error loading older messages: {:?}", e);
+            }
+        }
+    }
+    
+    fn export_transcript(&mut self, format: ExportFormat) {
+        let account = match self.account.lock().unwrap().clone() {
+            Some(account) => account,
+            None => return,
+        };
+        let target = self.export_peer.clone();
+        let passphrase = self.passphrase.clone();
+        let folder = PathBuf::from(&self.export_path);
+        let status = Arc::clone(&self.transfer_status);
+        
+        *status.lock().unwrap() = "Exporting...".to_string();
+        self.runtime.spawn_blocking(move || {
+            *status.lock().unwrap() = match Transcript::export(&account, target.as_deref(), format, &passphrase, &folder) {
+                Ok(path) => format!("Exported to {}", path.display()),
+                Err(e) => {
+                    warn!("Error exporting transcript: {:?}", e);
+                    format!("Export failed: {}", e)
+                }
+            };
+        });
+    }
+    
+    fn import_transcript(&mut self) {
+        let account = match self.account.lock().unwrap().clone() {
+            Some(account) => account,
+            None => return,
+        };
+        let passphrase = self.passphrase.clone();
+        let path = PathBuf::from(&self.export_path);
+        let status = Arc::clone(&self.transfer_status);
+        let reload_users = Arc::clone(&self.reload_users);
+        
+        *status.lock().unwrap() = "Importing...".to_string();
+        self.runtime.spawn_blocking(move || {
+            *status.lock().unwrap() = match Transcript::import(&account, &path, &passphrase) {
+                Ok(count) => {
+                    reload_users.store(true, std::sync::atomic::Ordering::Relaxed);
+                    format!("Imported {} messages", count)
+                },
+                Err(e) => {
+                    warn!("Error importing transcript: {:?}", e);
+                    format!("Import failed: {}", e)
+                }
+            };
+        });
+    }
+    
     pub fn new() -> Self {
         Self {
             input_text: String::new(),
             current_page: Page::Login,
             account: Arc::new(Mutex::new(None)),
+            connection: Arc::new(Mutex::new(None)),
             target: Arc::new(Mutex::new(None)),
             backup_user: init_load(),
             message: Arc::new(Mutex::new(vec![])),
+            history_page: 0,
+            history_top: false,
+            query_text: String::new(),
+            query_peer: None,
+            query_results: Vec::new(),
+            jump_to: None,
+            scroll_pending: false,
+            export_peer: None,
+            export_path: std::env::var("EXPORT_PATH").unwrap_or_else(|_| "./export/".to_string()),
+            passphrase: String::new(),
+            transfer_status: Arc::new(Mutex::new(String::new())),
+            reload_users: Arc::new(AtomicBool::new(false)),
             search_results: Arc::new(Mutex::new(vec![])),
+            prefix_search: false,
+            discoverable: Arc::new(Mutex::new(None)),
             load_user: Vec::new(),
             request_user: Arc::new(Mutex::new(Vec::new())),
+            outgoing_requests: Arc::new(Mutex::new(Vec::new())),
+            blocked_user: Arc::new(Mutex::new(Vec::new())),
+            block_input: String::new(),
+            decline_notify: true,
+            declined_by: Vec::new(),
+            key_changes: Arc::new(Mutex::new(Vec::new())),
+            log_warning: Arc::new(Mutex::new(None)),
+            recovery_input: String::new(),
             runtime: Arc::new(Runtime::new().unwrap()),
             refresh_task: None,
             should_run: Arc::new(AtomicBool::new(false)),
+            push_task: None,
+            push_events: None,
+            push_connected: Arc::new(AtomicBool::new(false)),
+            receipts: HashMap::new(),
         }
     }
 
@@ -87,27 +487,16 @@ impl AppState {
 
         if ui.button("Login").clicked() {
             if self.backup_user.contains(&self.input_text) {
-                match Account::load(self.input_text.to_string()) { 
-                    Ok(account) => {
-                        info!("Loaded account {:?}", account.name());
-                        self.account.lock().unwrap().replace(account);
+                match Account::load(self.input_text.to_string()).and_then(|account| {
+                    info!("Loaded account {:?}", account.name());
+                    self.load_user = init_load_user(account.name());
+                    sign_in_as(account, &self.account, &self.connection)
+                }) { 
+                    Ok(()) => {
                         self.current_page = Page::Search;
                         self.input_text.clear();
                         self.search_results.lock().unwrap().clear();
-                        self.load_user = init_load_user(&self.input_text);
-                        let request_user = Arc::clone(&self.request_user);
-                        let temp = self.input_text.clone();
-                        
-                        self.runtime.spawn(async move {
-                            match get_session_list(&temp).await {
-                                Ok(users) => {
-                                    *request_user.lock().unwrap() = users;
-                                },
-                                Err(e) => {
-                                    warn!("Error getting session list: {:?}", e);
-                                }
-                            }
-                        });
+                        self.refresh_requests();
                     },
                     Err(e) => {
                         ui.label("Error loading account");
@@ -116,13 +505,17 @@ impl AppState {
                 }    
             } else {
                 let account_clone = Arc::clone(&self.account);
+                let connection = Arc::clone(&self.connection);
                 let string_clone = self.input_text.clone();
+                let server = std::env::var("SERVER_URL").unwrap_or_default();
                 
                 self.runtime.spawn(async move {
-                    match Account::new(string_clone).await {
+                    match Account::new(&server, string_clone).await {
                         Ok(account) => {
                             info!("Created account {:?}", account.name());
-                            *account_clone.lock().unwrap() = Some(account);
+                            if let Err(e) = sign_in_as(account, &account_clone, &connection) {
+                                warn!("Error signing in: {:?}", e);
+                            }
                         },
                         Err(e) => {
                             info!("Error creating account: {:?}", e);
@@ -135,33 +528,54 @@ impl AppState {
                 self.search_results.lock().unwrap().clear();
             }
         }
+        
+        ui.horizontal(|ui| {
+            ui.label("Recovery code:");
+            ui.text_edit_singleline(&mut self.recovery_input);
+        });
+        
+        if ui.button("Reset Keys").clicked() {
+            let account_clone = Arc::clone(&self.account);
+            let connection = Arc::clone(&self.connection);
+            let string_clone = self.input_text.clone();
+            let recovery = self.recovery_input.clone();
+            let server = std::env::var("SERVER_URL").unwrap_or_default();
+            
+            self.runtime.spawn(async move {
+                match Account::reset(&server, string_clone, &recovery).await {
+                    Ok(account) => {
+                        info!("Reset keys of {:?}", account.name());
+                        if let Err(e) = sign_in_as(account, &account_clone, &connection) {
+                            warn!("Error signing in: {:?}", e);
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Error resetting keys: {:?}", e);
+                    }
+                }
+            });
+            
+            self.current_page = Page::Search;
+            self.input_text.clear();
+            self.recovery_input.clear();
+            self.search_results.lock().unwrap().clear();
+        }
 
         ui.add_space(10.0);
         ui.label("Recent Login Users:");
 
         for result in &self.backup_user {
             if ui.button(result).clicked() {
-                match Account::load(result.to_string()) { 
-                    Ok(account) => {
-                        info!("Loaded account {:?}", account.name());
-                        self.account.lock().unwrap().replace(account);
+                match Account::load(result.to_string()).and_then(|account| {
+                    info!("Loaded account {:?}", account.name());
+                    sign_in_as(account, &self.account, &self.connection)
+                }) { 
+                    Ok(()) => {
                         self.current_page = Page::Search;
                         self.input_text.clear();
                         self.search_results.lock().unwrap().clear();
                         self.load_user = init_load_user(result);
-                        let request_user = Arc::clone(&self.request_user);
-                        let temp = result.clone();
-                        
-                        self.runtime.spawn(async move {
-                            match get_session_list(&temp).await {
-                                Ok(users) => {
-                                    *request_user.lock().unwrap() = users;
-                                },
-                                Err(e) => {
-                                    warn!("Error getting session list: {:?}", e);
-                                }
-                            }
-                        });
+                        self.refresh_requests();
                     },
                     Err(e) => {
                         ui.label("Error loading account");
@@ -178,7 +592,17 @@ impl AppState {
                 self.current_page = Page::Login;
                 self.backup_user = init_load();
                 self.account.lock().unwrap().take();
+                self.key_changes.lock().unwrap().clear();
+                self.log_warning.lock().unwrap().take();
+                self.discoverable.lock().unwrap().take();
+                self.blocked_user.lock().unwrap().clear();
+                self.outgoing_requests.lock().unwrap().clear();
+                self.declined_by.clear();
+                self.stop_push();
+                self.connection.lock().unwrap().take();
                 self.input_text.clear();
+                self.query_text.clear();
+                self.query_results.clear();
             }
             ui.heading("Search User");
         });
@@ -190,18 +614,58 @@ impl AppState {
             "None"
         }));
         
+        if let Some(code) = self.account.lock().unwrap().as_ref().and_then(|account| account.recovery_code()) {
+            ui.label(format!("Recovery code (write it down, it is shown only once): {}", code));
+        }
+        
+        if let Some(warning) = self.log_warning.lock().unwrap().as_ref() {
+            ui.colored_label(egui::Color32::RED, format!("Warning: {}", warning));
+        }
+        
+        for account in &self.declined_by {
+            ui.colored_label(egui::Color32::RED, format!("{} declined your session request", account));
+        }
+        
+        for change in self.key_changes.lock().unwrap().iter() {
+            ui.colored_label(egui::Color32::RED, format!(
+                "Warning: {} changed their identity key at {} ({}...)",
+                change.target,
+                format_timestamp(change.timestamp),
+                &change.ik_public[..16.min(change.ik_public.len())]
+            ));
+        }
+        
+        let current = *self.discoverable.lock().unwrap();
+        if let Some(mut value) = current {
+            if ui.checkbox(&mut value, "Discoverable (others can find me by prefix search)").changed() {
+                let discoverable = Arc::clone(&self.discoverable);
+                
+                self.spawn_connected(move |connection| async move {
+                    match set_discoverable(&connection, Some(value)).await {
+                        Ok(value) => {
+                            *discoverable.lock().unwrap() = Some(value);
+                        },
+                        Err(e) => {
+                            warn!("Error changing discovery setting: {:?}", e);
+                        }
+                    }
+                });
+            }
+        }
+        
         ui.horizontal(|ui| {
             ui.label("Search:");
             ui.text_edit_singleline(&mut self.input_text);
         });
+        ui.checkbox(&mut self.prefix_search, "Prefix search (discoverable accounts only)");
 
         if ui.button("Search").clicked() {
             let search_results = Arc::clone(&self.search_results);
             let input_text = self.input_text.clone();
-            let account = self.account.lock().unwrap().as_ref().unwrap().name().to_string();
+            let prefix = self.prefix_search;
             
-            self.runtime.spawn(async move {
-                match search(&account, &input_text).await {
+            self.spawn_connected(move |connection| async move {
+                match search(&connection, &input_text, prefix).await {
                     Ok(results) => {
                         info!("Search results: {:?}", results);
                         *search_results.lock().unwrap() = results;
@@ -217,19 +681,21 @@ impl AppState {
 
         for result in self.search_results.lock().unwrap().iter() {
             if ui.button(result).clicked() {
-                if self.request_user.lock().unwrap().contains(result) {
+                if self.request_user.lock().unwrap().iter().any(|request| request.account == *result) {
+                    // Accepting their request instead of starting another session avoids two
+                    // sessions with the same peer.
+                    self.request_user.lock().unwrap().retain(|request| request.account != *result);
                     let input_text = result.clone();
                     let target = Arc::clone(&self.target);
                     let account = self.account.clone();
 
-                    self.runtime.spawn(async move {
-                        match get_session(&input_text, account).await {
-                            Ok(session) => {
-                                info!("Got session for {input_text}");
-                                *target.lock().unwrap() = Some(session);
+                    self.spawn_connected(move |connection| async move {
+                        match RequestPayload::receive(&connection, input_text, account).await {
+                            Ok((session, _)) => {
+                                target.lock().unwrap().replace(session);
                             },
                             Err(e) => {
-                                warn!("Error getting session: {:?}", e);
+                                warn!("Error receiving request: {:?}", e);
                             }
                         }
                     });
@@ -253,8 +719,8 @@ impl AppState {
                     let target = Arc::clone(&self.target);
                     let account = self.account.clone();
 
-                    self.runtime.spawn(async move {
-                        match get_session(&input_text, account).await {
+                    self.spawn_connected(move |connection| async move {
+                        match get_session(&connection, &input_text, account).await {
                             Ok(session) => {
                                 info!("Got session for {input_text}");
                                 *target.lock().unwrap() = Some(session);
@@ -289,26 +755,226 @@ impl AppState {
             }
         }
         
-        ui.label("Request:");
-        for result in &*self.request_user.lock().unwrap() {
-            if ui.button(result).clicked() {
-                let result = result.clone();
-                let account = self.account.clone();
-                let target = Arc::clone(&self.target);
-                self.runtime.spawn(async move {
-                    match RequestPayload::receive(result, account).await {
-                        Ok(session) => {
-                            target.lock().unwrap().replace(session);
-                        },
-                        Err(e) => {
-                            warn!("Error receiving request: {:?}", e);
-                        }
+        ui.add_space(10.0);
+        ui.label("Messages:");
+        
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.query_text);
+            egui::ComboBox::from_id_salt("query_peer")
+                .selected_text(self.query_peer.as_deref().unwrap_or("All"))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.query_peer, None, "All");
+                    for user in &self.load_user {
+                        ui.selectable_value(&mut self.query_peer, Some(user.clone()), user);
                     }
                 });
-                self.current_page = Page::Chat;
-                self.input_text.clear();
+            
+            if ui.button("Find").clicked() {
+                let account = self.account.lock().unwrap().clone();
+                if let Some(account) = account {
+                    let results = SearchIndex::load(&account)
+                        .and_then(|index| index.search(&account, &self.query_text, self.query_peer.as_deref()));
+                    
+                    match results {
+                        Ok(results) => self.query_results = results,
+                        Err(e) => { warn!("Error searching messages: {:?}", e); }
+                    }
+                }
+            }
+        });
+        
+        let mut open = None;
+        for hit in &self.query_results {
+            if ui.button(format!("{}: {} - {}", hit.target, hit.message, hit.message.timestamp())).clicked() {
+                open = Some((hit.target.clone(), hit.message.id));
+            }
+        }
+        
+        if let Some((result, id)) = open {
+            match SessionKey::load(&result, self.account.clone()) {
+                Ok(session) => {
+                    info!("Loaded session {:?}", session.name());
+                    self.target.lock().unwrap().replace(session);
+                    self.jump_to = Some(id);
+                    self.current_page = Page::Chat;
+                    self.input_text.clear();
+                    self.query_results.clear();
+                },
+                Err(e) => {
+                    ui.label("Error loading session");
+                    warn!("Error loading session: {:?}", e);
+                }
             }
         }
+        
+        ui.add_space(10.0);
+        ui.label("Transcripts:");
+        
+        if self.reload_users.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            if let Some(account) = self.account.lock().unwrap().as_ref() {
+                self.load_user = init_load_user(account.name());
+            }
+        }
+        
+        ui.horizontal(|ui| {
+            ui.label("Path:");
+            ui.text_edit_singleline(&mut self.export_path);
+            ui.label("Passphrase:");
+            ui.add(egui::TextEdit::singleline(&mut self.passphrase).password(true));
+        });
+        
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("export_peer")
+                .selected_text(self.export_peer.as_deref().unwrap_or("All"))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.export_peer, None, "All");
+                    for user in &self.load_user {
+                        ui.selectable_value(&mut self.export_peer, Some(user.clone()), user);
+                    }
+                });
+            
+            for (label, format) in [
+                ("Export JSON", ExportFormat::Json),
+                ("Export Text", ExportFormat::Text),
+                ("Export Archive", ExportFormat::Archive),
+            ] {
+                if ui.button(label).clicked() {
+                    self.export_transcript(format);
+                }
+            }
+            
+            if ui.button("Import Archive").clicked() {
+                self.import_transcript();
+            }
+        });
+        
+        let status = self.transfer_status.lock().unwrap().clone();
+        if !status.is_empty() {
+            ui.label(status);
+        }
+        
+        ui.add_space(10.0);
+        ui.horizontal(|ui| {
+            ui.label("Request:");
+            ui.checkbox(&mut self.decline_notify, "Tell declined users");
+        });
+        let mut accepted = None;
+        let mut declined = None;
+        let mut blocked = None;
+        for request in &*self.request_user.lock().unwrap() {
+            ui.horizontal(|ui| {
+                if ui.button(&request.account).clicked() {
+                    accepted = Some(request.account.clone());
+                }
+                ui.label(format_age(request.timestamp));
+                if ui.button("Decline").clicked() {
+                    declined = Some(request.account.clone());
+                }
+                if ui.button("Block").clicked() {
+                    blocked = Some(request.account.clone());
+                }
+            });
+        }
+        
+        if let Some(result) = accepted {
+            let account = self.account.clone();
+            let target = Arc::clone(&self.target);
+            self.spawn_connected(move |connection| async move {
+                match RequestPayload::receive(&connection, result, account).await {
+                    Ok((session, _)) => {
+                        target.lock().unwrap().replace(session);
+                    },
+                    Err(e) => {
+                        warn!("Error receiving request: {:?}", e);
+                    }
+                }
+            });
+            self.current_page = Page::Chat;
+            self.input_text.clear();
+        }
+        
+        if let Some(result) = declined {
+            self.request_user.lock().unwrap().retain(|request| request.account != result);
+            let notify = self.decline_notify;
+            self.spawn_connected(move |connection| async move {
+                if let Err(e) = RequestPayload::decline(&connection, &result, notify).await {
+                    warn!("Error declining request: {:?}", e);
+                }
+            });
+        }
+        
+        ui.add_space(10.0);
+        ui.label("Outgoing:");
+        let mut cancelled = None;
+        for request in &*self.outgoing_requests.lock().unwrap() {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} ({})", request.account, format_age(request.timestamp)));
+                if ui.button("Cancel").clicked() {
+                    cancelled = Some(request.account.clone());
+                }
+            });
+        }
+        
+        if let Some(result) = cancelled {
+            self.outgoing_requests.lock().unwrap().retain(|request| request.account != result);
+            self.spawn_connected(move |connection| async move {
+                if let Err(e) = RequestPayload::cancel(&connection, &result).await {
+                    warn!("Error cancelling request: {:?}", e);
+                }
+            });
+        }
+        
+        ui.add_space(10.0);
+        ui.label("Blocked:");
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.block_input);
+            if ui.button("Block").clicked() && !self.block_input.is_empty() {
+                blocked = Some(std::mem::take(&mut self.block_input));
+            }
+        });
+        if let Some(result) = blocked {
+            self.block(result);
+        }
+        
+        let mut unblocked = None;
+        for result in &*self.blocked_user.lock().unwrap() {
+            ui.horizontal(|ui| {
+                ui.label(result);
+                if ui.button("Unblock").clicked() {
+                    unblocked = Some(result.clone());
+                }
+            });
+        }
+        
+        if let Some(result) = unblocked {
+            self.blocked_user.lock().unwrap().retain(|user| *user != result);
+            self.spawn_connected(move |connection| async move {
+                if let Err(e) = unblock_user(&connection, &result).await {
+                    warn!("Error unblocking {}: {:?}", result, e);
+                }
+            });
+        }
+    }
+    
+    /// Blocks `target` on the server and drops its pending request from the list.
+    fn block(&mut self, target: String) {
+        self.request_user.lock().unwrap().retain(|request| request.account != target);
+        let blocked_user = Arc::clone(&self.blocked_user);
+        
+        self.spawn_connected(move |connection| async move {
+            match block_user(&connection, &target).await {
+                Ok(()) => {
+                    let mut blocked_user = blocked_user.lock().unwrap();
+                    if !blocked_user.contains(&target) {
+                        blocked_user.push(target);
+                        blocked_user.sort();
+                    }
+                },
+                Err(e) => {
+                    warn!("Error blocking {}: {:?}", target, e);
+                }
+            }
+        });
     }
 
     fn show_chat_page(&mut self, ui: &mut egui::Ui) {
@@ -316,11 +982,9 @@ impl AppState {
             let target = Arc::clone(&self.target);
             let runtime = self.runtime.clone();
             let account = {
-                match self.account.lock().unwrap().as_ref() { 
-                    Some(account) => Some(account.name().to_string()),
-                    None => None,
-                }
+                self.account.lock().unwrap().clone()
             };
+            let connection = self.connection.lock().unwrap().clone();
             
             let target_name = {
                 match self.target.lock().unwrap().as_ref() {
@@ -329,35 +993,77 @@ impl AppState {
                 }
             };
             
-            if let (Some(account), Some(target_name)) = (account, target_name) {
+            if let (Some(account), Some(connection), Some(target_name)) = (account, connection, target_name) {
+                let history = match self.jump_to {
+                    Some(id) => MessageHistory::since(&account, &target_name, (id as usize).saturating_sub(1) / HISTORY_PAGE_SIZE),
+                    None => MessageHistory::latest(&account, &target_name),
+                };
+                
+                match history {
+                    Ok((page, messages)) => {
+                        *self.message.lock().unwrap() = messages;
+                        self.history_page = page;
+                        self.history_top = false;
+                        self.scroll_pending = self.jump_to.is_some();
+                    },
+                    Err(e) => {
+                        warn!("Error loading history: {:?}", e);
+                    }
+                }
+                
                 self.should_run.store(true, std::sync::atomic::Ordering::Relaxed);
                 let should_run = Arc::clone(&self.should_run);
                 let message = Arc::clone(&self.message);
+                let push_connected = Arc::clone(&self.push_connected);
                 
+                // New messages normally arrive over the push connection; polling only covers the
+                // time it is down.
                 self.refresh_task = Some(runtime.spawn(async move {
-                    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+                    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(POLL_INTERVAL));
                     while should_run.load(std::sync::atomic::Ordering::Relaxed) {
                         interval.tick().await;
+                        if push_connected.load(std::sync::atomic::Ordering::Relaxed) {
+                            continue;
+                        }
                         
-                        match MessagePayload::receive(account.to_string(), target_name.to_string()).await {
-                            Ok(messages) => {
-                                let mut temp = vec![];
-                                for message in messages {
-                                    if let Some(target) = target.lock().unwrap().as_mut() {
-                                        match target.revive_message(message.message, message.timestamp, &account) {
-                                            Ok(result) => {
-                                                temp.push(result);
-                                            },
-                                            Err(e) => {
-                                                warn!("Error reviving message: {:?}", e);
-                                            }
+                        // Follow the cursors until the queue is drained, acknowledging page by page.
+                        let mut after = None;
+                        loop {
+                            let (messages, next) = match MessagePayload::receive(&connection, target_name.to_string(), after).await {
+                                Ok(page) => page,
+                                Err(e) => {
+                                    warn!("Error refreshing messages: {:?}", e);
+                                    break;
+                                }
+                            };
+                            
+                            let mut temp = vec![];
+                            let mut acked = vec![];
+                            for message in messages {
+                                if let Some(target) = target.lock().unwrap().as_mut() {
+                                    let id = message.id;
+                                    match receive_message(&account, target, message) {
+                                        Ok(result) => {
+                                            temp.extend(result);
+                                            acked.push(id);
+                                        },
+                                        Err(e) => {
+                                            warn!("Error reviving message: {:?}", e);
                                         }
                                     }
                                 }
-                                message.lock().unwrap().extend(temp);
-                            },
-                            Err(e) => {
-                                warn!("Error refreshing messages: {:?}", e);
+                            }
+                            message.lock().unwrap().extend(temp);
+                            
+                            if !acked.is_empty() {
+                                if let Err(e) = ack(&connection, &acked, &[]).await {
+                                    warn!("Error acknowledging messages: {:?}", e);
+                                }
+                            }
+                            
+                            match next {
+                                Some(next) => after = Some(next),
+                                None => break,
                             }
                         }
                     }
@@ -373,41 +1079,72 @@ impl AppState {
                 self.target.lock().unwrap().take();
                 self.input_text.clear();
                 self.load_user = init_load_user(&self.account.lock().unwrap().as_ref().unwrap().name());
-                self.refresh_task.take();
+                self.message.lock().unwrap().clear();
+                self.jump_to = None;
+                self.scroll_pending = false;
+                if let Some(task) = self.refresh_task.take() {
+                    task.abort();
+                }
                 
-                let temp = self.account.lock().unwrap().as_ref().unwrap().name().to_string();
-                let request_user = Arc::clone(&self.request_user);
-                self.runtime.spawn(async move {
-                    match get_session_list(&temp).await {
-                        Ok(users) => {
-                            *request_user.lock().unwrap() = users;
-                        },
-                        Err(e) => {
-                            warn!("Error getting session list: {:?}", e);
-                        }
-                    }
-                });
+                self.refresh_requests();
             }
             ui.heading(format!("Chat with {}", self.target.lock().unwrap().as_ref()
                 .map_or("Default Name", |target| target.name())
             ));
         });
         
-        egui::ScrollArea::vertical().show(ui, |ui| {
+        let delivered = self.target.lock().unwrap().as_ref()
+            .and_then(|target| self.receipts.get(target.name()).copied());
+        if let Some(time) = delivered {
+            ui.label(format!("Delivered {}", format_timestamp(time)));
+        }
+        
+        let highlight = self.jump_to;
+        let mut scrolled = false;
+        let output = egui::ScrollArea::vertical().stick_to_bottom(highlight.is_none()).show(ui, |ui| {
             let messages = self.message.lock().unwrap();
             for msg in messages.iter() {
-                if msg.sender {
-                    ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
-                        ui.label(format!("{} - {}", msg, msg.timestamp()));
-                    });
+                let layout = if msg.sender {
+                    egui::Layout::right_to_left(egui::Align::TOP)
                 } else {
-                    ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
-                        ui.label(format!("{} - {}", msg, msg.timestamp()));
-                    });
-                }
+                    egui::Layout::left_to_right(egui::Align::TOP)
+                };
+                
+                ui.with_layout(layout, |ui| {
+                    let mut label = format!("{} - {}", msg, msg.timestamp());
+                    if let Some(received) = msg.received {
+                        label += &format!(" (received {})", format_timestamp(received));
+                    }
+                    if msg.pending {
+                        label += " - not sent";
+                    }
+                    let text = if msg.skewed() {
+                        egui::RichText::new(label + " - sender clock differs").color(egui::Color32::RED)
+                    } else {
+                        egui::RichText::new(label)
+                    };
+                    if highlight == Some(msg.id) {
+                        let response = ui.label(text.strong().underline());
+                        if self.scroll_pending {
+                            response.scroll_to_me(Some(egui::Align::Center));
+                            scrolled = true;
+                        }
+                    } else {
+                        ui.label(text);
+                    }
+                });
             }
             
         });
+        if scrolled {
+            self.scroll_pending = false;
+        }
+        
+        let at_top = output.state.offset.y <= 0.0;
+        if at_top && !self.history_top && self.history_page > 0 {
+            self.load_older_messages();
+        }
+        self.history_top = at_top;
 
         ui.separator();
 
@@ -423,6 +1160,9 @@ impl AppState {
 
 impl eframe::App for AppState {
     fn update(&mut self, ctx: &egui::Context, _: &mut eframe::Frame) {
+        self.start_push();
+        self.handle_push_events();
+        
         egui::CentralPanel::default().show(ctx, |ui| {
             match self.current_page {
                 Page::Login => self.show_login_page(ui),
@@ -0,0 +1,193 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::Local;
+use glob::glob;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::account::Account;
+use crate::file::{backup_path, valid_name, AccountLock, LocalArchive, MessageHistory, SessionKey};
+use crate::message::Message;
+use crate::support::open;
+use crate::util::BACKUP_ARCHIVE;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupFile {
+    path: String,
+    data: String,
+    digest: String,
+}
+
+/// Every file of an account folder that is needed to carry the account to another machine:
+/// `keys.json`, one `key.json` per session and, optionally, the sealed history pages.
+/// The search index is left out and rebuilt from the history after a restore.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountBackup {
+    account: String,
+    created: i64,
+    files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: Vec<String>,
+    pub skipped: Vec<String>,
+    /// Peers with another session locally than in the backup, which was left as it was.
+    pub conflicts: Vec<String>,
+}
+
+fn account_folder(account: &str) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(backup_path()?.join(account))
+}
+
+fn local_path(folder: &Path, path: &str) -> PathBuf {
+    path.split('/').fold(folder.to_path_buf(), |p, c| p.join(c))
+}
+
+impl AccountBackup {
+    pub fn create(account: &str, history: bool) -> Result<Self, Box<dyn Error>> {
+        let folder = account_folder(account)?;
+        if !folder.join("keys.json").exists() {
+            return Err(format!("No local keys found for {}", account).into());
+        }
+
+        let mut files = vec![Self::read(&folder, "keys.json")?];
+
+        for entry in fs::read_dir(&folder)? {
+            let entry = entry?;
+            if !entry.path().is_dir() || !entry.path().join("key.json").exists() { continue; }
+
+            let peer = entry.file_name().to_string_lossy().to_string();
+            files.push(Self::read(&folder, &format!("{}/key.json", peer))?);
+
+            if history {
+                let pattern = entry.path().join("history").join("*.bin");
+                for page in glob(&pattern.to_string_lossy())? {
+                    let name = page?.file_name().unwrap().to_string_lossy().to_string();
+                    files.push(Self::read(&folder, &format!("{}/history/{}", peer, name))?);
+                }
+            }
+        }
+
+        info!("Packed {} files for {}", files.len(), account);
+        Ok(Self { account: account.to_string(), created: Local::now().timestamp(), files })
+    }
+
+    fn read(folder: &Path, path: &str) -> Result<BackupFile, Box<dyn Error>> {
+        let data = fs::read(local_path(folder, path))?;
+
+        Ok(BackupFile {
+            path: path.to_string(),
+            digest: hex::encode(Sha256::digest(&data)),
+            data: hex::encode(data),
+        })
+    }
+
+    fn data(&self, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let file = self.files.iter().find(|f| f.path == path).ok_or(format!("Missing {} in backup", path))?;
+        Ok(hex::decode(&file.data)?)
+    }
+
+    pub fn save(&self, output: &Path, passphrase: &str) -> Result<(), Box<dyn Error>> {
+        if passphrase.is_empty() { return Err("A passphrase is required for backups".into()); }
+        LocalArchive::seal(BACKUP_ARCHIVE, passphrase, &serde_json::to_vec(self)?)?.save(output)
+    }
+
+    pub fn load(input: &Path, passphrase: &str) -> Result<Self, Box<dyn Error>> {
+        let backup: Self = serde_json::from_slice(&LocalArchive::load(input)?.open(BACKUP_ARCHIVE, passphrase)?)?;
+        backup.verify()?;
+        Ok(backup)
+    }
+
+    /// Checks the digest of every file and rejects paths that would escape the account folder.
+    fn verify(&self) -> Result<(), Box<dyn Error>> {
+        if !valid_name(&self.account) {
+            return Err(format!("Invalid account name {:?} in backup", self.account).into());
+        }
+
+        for file in &self.files {
+            if !file.path.split('/').all(valid_name) {
+                return Err(format!("Invalid path {:?} in backup", file.path).into());
+            }
+            if hex::encode(Sha256::digest(hex::decode(&file.data)?)) != file.digest {
+                return Err(format!("Integrity check failed for {}", file.path).into());
+            }
+        }
+
+        self.data("keys.json").map(|_| ())
+    }
+
+    /// Writes the backup into the backup folder. The identity is only written if the account does not
+    /// exist locally or holds the same identity key. A session is only written if the local copy
+    /// is the same session with fewer steps, so a stale backup never rolls a ratchet back, and a
+    /// different local session is left for the user to settle. Archived history is merged into
+    /// the local one.
+    pub fn restore(&self) -> Result<RestoreReport, Box<dyn Error>> {
+        let folder = account_folder(&self.account)?;
+        let _lock = AccountLock::acquire(&self.account)?;
+        let mut report = RestoreReport::default();
+
+        let keys = self.data("keys.json")?;
+        let local_keys = folder.join("keys.json");
+        if local_keys.exists() {
+            let ik_public = |data: &[u8]| -> Result<serde_json::Value, Box<dyn Error>> {
+                Ok(serde_json::from_slice::<serde_json::Value>(data)?["ik_public"].clone())
+            };
+            if ik_public(&fs::read(&local_keys)?)? != ik_public(&keys)? {
+                return Err(format!("{} already exists locally with a different identity key", self.account).into());
+            }
+        } else {
+            fs::create_dir_all(&folder)?;
+            fs::write(&local_keys, &keys)?;
+        }
+        let account = Account::load(self.account.clone())?;
+
+        for file in self.files.iter().filter(|f| f.path.ends_with("/key.json")) {
+            let peer = file.path.trim_end_matches("/key.json").to_string();
+            let archived: SessionKey = serde_json::from_slice(&hex::decode(&file.data)?)?;
+            let path = local_path(&folder, &file.path);
+
+            if path.exists() {
+                let local: SessionKey = serde_json::from_slice(&fs::read(&path)?)?;
+                if local.id.is_none() || local.id != archived.id {
+                    warn!("Kept the local session with {}, the backup holds another one", peer);
+                    report.conflicts.push(peer);
+                    continue;
+                }
+                if local.steps >= archived.steps {
+                    warn!("Kept newer local session with {}", peer);
+                    report.skipped.push(peer);
+                    continue;
+                }
+            }
+
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, hex::decode(&file.data)?)?;
+            report.restored.push(peer);
+        }
+
+        // History is merged for every peer, also where the local session was kept.
+        let key = account.storage_key();
+        for file in self.files.iter().filter(|f| f.path.ends_with("/key.json")) {
+            let peer = file.path.trim_end_matches("/key.json");
+            let prefix = format!("{}/history/", peer);
+            let mut pages: Vec<(usize, &BackupFile)> = self.files.iter()
+                .filter_map(|f| Some((f.path.strip_prefix(&prefix)?.strip_suffix(".bin")?.parse().ok()?, f)))
+                .collect();
+            if pages.is_empty() { continue; }
+            pages.sort_by_key(|(page, _)| *page);
+
+            let mut messages: Vec<Message> = vec![];
+            for (_, page) in pages {
+                messages.extend(serde_json::from_slice::<Vec<Message>>(&open(&key, &hex::decode(&page.data)?)?)?);
+            }
+            let added = MessageHistory::merge(&account, peer, messages)?;
+            info!("Merged {} archived messages with {}", added, peer);
+        }
+
+        info!("Restored {} sessions for {}, kept {} newer and {} different local sessions",
+            report.restored.len(), self.account, report.skipped.len(), report.conflicts.len());
+        Ok(report)
+    }
+}
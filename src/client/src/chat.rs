@@ -0,0 +1,106 @@
+use std::error::Error;
+use log::{info, warn};
+use serde_json::{json, Value};
+use crate::account::Account;
+use crate::file::{MessageHistory, SessionKey};
+use crate::message::Message;
+use crate::session::{Handshake, Session};
+use crate::socket::{Connection, MessagePayload, RequestPayload};
+
+/// Decrypts a message from the peer of `session` and stores it in the history. Messages the
+/// peer sent under another session before both sides settled on this one are read with the
+/// alternate sessions kept for it, which are dropped once the peer writes under this one.
+/// Returns `None` for a message read before, which only needs acknowledging again.
+pub fn receive_message(account: &Account, session: &mut Session, payload: MessagePayload) -> Result<Option<Message>, Box<dyn Error>> {
+    if session.seen(payload.seq) {
+        info!("Skipped message {} from {}, it was read before", payload.seq, session.name());
+        return Ok(None);
+    }
+    
+    // Messages queued before the server recorded when it received them only have the time the
+    // sender gave.
+    let received = if payload.received > 0 { payload.received } else { payload.timestamp };
+    let message = match session.revive_message(payload.message.clone(), received, payload.seq, account.name()) {
+        Ok(message) => {
+            if let Err(e) = SessionKey::remove_alternates(session.name(), account.name()) {
+                warn!("Error removing alternate sessions with {}: {:?}", session.name(), e);
+            }
+            message
+        },
+        Err(e) => {
+            let message = SessionKey::load_alternates(session.name(), account.name())?
+                .into_iter()
+                .find_map(|mut alternate| alternate.revive_message(payload.message.clone(), received, payload.seq, account.name()).ok())
+                .ok_or(e)?;
+            session.mark_seen(payload.seq);
+            SessionKey::overload(session, account.name())?;
+            message
+        },
+    };
+    
+    match MessageHistory::append(account, session.name(), message.clone()) {
+        Ok(stored) => Ok(Some(stored)),
+        Err(e) => {
+            warn!("Error saving message: {:?}", e);
+            Ok(Some(message))
+        }
+    }
+}
+
+/// A message written under a session and what still has to go to the server for it.
+pub struct Outgoing {
+    pub message: Message,
+    payload: String,
+    first: bool,
+    request: Option<Handshake>,
+}
+
+/// Stores `text` in the history with the peer of `session` and encrypts it under the session.
+pub fn write_message(account: &Account, session: &mut Session, text: String) -> Result<Outgoing, Box<dyn Error>> {
+    let mut message = Message::new(text);
+    match MessageHistory::append(account, session.name(), message.clone()) {
+        Ok(stored) => message = stored,
+        Err(e) => { warn!("Error saving message: {:?}", e); }
+    }
+    
+    let first = session.handshake.as_ref().is_some_and(|handshake| handshake.message.is_none());
+    let payload = session.add_message(message.clone(), account.name())?;
+    Ok(Outgoing { message, payload, first, request: session.pending_request().cloned() })
+}
+
+impl Outgoing {
+    /// Sends the message to `target` over `connection`. The request carries the first message. Until it is
+    /// delivered it goes out again before every message, which the responder reads after
+    /// accepting. `confirm` is called once the server holds the request, to forget the handshake.
+    /// Returns the message as stored once the server holds it, no longer pending.
+    pub async fn send(self, connection: &Connection, account: &Account, target: &str, confirm: impl FnOnce()) -> Result<Message, Box<dyn Error>> {
+        if let Some(handshake) = &self.request {
+            RequestPayload::send(connection, account.ik().public_key, handshake, target).await?;
+            confirm();
+        }
+        if !(self.request.is_some() && self.first) {
+            MessagePayload::send(connection, target, self.payload, self.message.timestamp).await?;
+        }
+        
+        let mut message = self.message;
+        message.pending = false;
+        if let Err(e) = MessageHistory::settle(account, target, message.id) {
+            warn!("Error saving message: {:?}", e);
+        }
+        Ok(message)
+    }
+}
+
+/// The JSON form of a message with `peer` that the command line and the daemon hand out.
+pub fn message_json(peer: &str, message: &Message) -> Value {
+    json!({
+        "peer": peer,
+        "id": message.id,
+        "outgoing": message.sender,
+        "text": message.text,
+        "timestamp": message.timestamp,
+        "received": message.received,
+        "skewed": message.skewed(),
+        "pending": message.pending,
+    })
+}
@@ -0,0 +1,185 @@
+use std::error::Error;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use clap::Subcommand;
+use log::warn;
+use serde_json::{json, Value};
+use crate::account::Account;
+use crate::chat::{message_json, receive_message, write_message};
+use crate::daemon::default_socket;
+use crate::file::{init_load, init_load_user, SessionKey};
+use crate::session::Session;
+use crate::socket::{
+    ack, get_outgoing_list, get_session, get_session_list, search, sign_in, Connection, MessagePayload, RequestPayload
+};
+
+/// Commands of the headless client. Each prints one JSON object on stdout, or `{"error": …}`
+/// and a failing exit status.
+#[derive(Subcommand, Clone)]
+pub enum CliCommand {
+    /// Register a new account and print its recovery code
+    Create { account: String },
+    /// List the accounts stored locally
+    Accounts,
+    /// Find an account by its exact name, or discoverable accounts by prefix
+    Search {
+        target: String,
+        #[arg(long)]
+        prefix: bool,
+    },
+    /// Start a session with an account, or accept its request if it sent one
+    Start { target: String },
+    /// List the pending session requests in both directions
+    Requests,
+    /// Accept the session request of an account
+    Accept { target: String },
+    /// Send a message to an account with a session
+    Send { target: String, text: String },
+    /// Read the pending messages from one account, or from every account with a session
+    Receive { target: Option<String> },
+    /// List the sessions stored locally
+    Sessions,
+}
+
+/// Loads the stored keys of `account` and signs in with them. While its daemon runs, commands
+/// have to go through the daemon, which holds the sessions.
+fn load_account(account: Option<&str>) -> Result<(Arc<Mutex<Option<Account>>>, Connection), Box<dyn Error>> {
+    let account = Account::load(account.ok_or("--account is required")?.to_string())?;
+    let socket = default_socket(account.name())?;
+    if UnixStream::connect(&socket).is_ok() {
+        return Err(format!("The daemon of {} is running, send commands to it on {}", account.name(), socket.display()).into());
+    }
+    let connection = sign_in(&account)?;
+    Ok((Arc::new(Mutex::new(Some(account))), connection))
+}
+
+fn load_session(target: &str, account: Arc<Mutex<Option<Account>>>) -> Result<Session, Box<dyn Error>> {
+    SessionKey::load(target, account).map_err(|e| format!("No session with {}: {}", target, e).into())
+}
+
+/// Reads everything queued from `target`, acknowledging page by page.
+async fn receive_from(connection: &Connection, account: &Account, target: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+    let mut session = load_session(target, Arc::new(Mutex::new(Some(account.clone()))))?;
+    let mut messages = vec![];
+    let mut after = None;
+
+    loop {
+        let (page, next) = MessagePayload::receive(connection, target.to_string(), after).await?;
+        let mut acked = vec![];
+        for payload in page {
+            let id = payload.id;
+            match receive_message(account, &mut session, payload) {
+                Ok(message) => {
+                    messages.extend(message.map(|message| message_json(target, &message)));
+                    acked.push(id);
+                },
+                Err(e) => { warn!("Error reviving message from {}: {:?}", target, e); }
+            }
+        }
+        if !acked.is_empty() {
+            ack(connection, &acked, &[]).await?;
+        }
+
+        match next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+    Ok(messages)
+}
+
+async fn execute(account: Option<&str>, command: CliCommand) -> Result<Value, Box<dyn Error>> {
+    match command {
+        CliCommand::Create { account } => {
+            let account = Account::new(&std::env::var("SERVER_URL")?, account).await?;
+            Ok(json!({ "account": account.name(), "recovery_code": account.recovery_code() }))
+        },
+        CliCommand::Accounts => Ok(json!({ "accounts": init_load() })),
+        CliCommand::Search { target, prefix } => {
+            let (_, connection) = load_account(account)?;
+            Ok(json!({ "results": search(&connection, &target, prefix).await? }))
+        },
+        CliCommand::Start { target } => {
+            let (account, connection) = load_account(account)?;
+            let requested = get_session_list(&connection).await?.iter().any(|request| request.account == target);
+            if requested {
+                let (_, first) = RequestPayload::receive(&connection, target.clone(), account).await?;
+                let messages: Vec<Value> = first.iter().map(|message| message_json(&target, message)).collect();
+                Ok(json!({ "target": target, "accepted": true, "messages": messages }))
+            } else {
+                get_session(&connection, &target, account).await?;
+                Ok(json!({ "target": target, "accepted": false, "messages": [] }))
+            }
+        },
+        CliCommand::Requests => {
+            let (_, connection) = load_account(account)?;
+            Ok(json!({ "incoming": get_session_list(&connection).await?, "outgoing": get_outgoing_list(&connection).await? }))
+        },
+        CliCommand::Accept { target } => {
+            let (account, connection) = load_account(account)?;
+            let (_, first) = RequestPayload::receive(&connection, target.clone(), account).await?;
+            let messages: Vec<Value> = first.iter().map(|message| message_json(&target, message)).collect();
+            Ok(json!({ "target": target, "messages": messages }))
+        },
+        CliCommand::Send { target, text } => {
+            let (shared, connection) = load_account(account)?;
+            let account = shared.lock().unwrap().clone().ok_or("Not signed in")?;
+            let mut session = load_session(&target, shared)?;
+            let outgoing = write_message(&account, &mut session, text)?;
+
+            let confirm = || {
+                if let Err(e) = session.confirm_request(account.name()) {
+                    warn!("Error saving the session with {}: {:?}", target, e);
+                }
+            };
+            let message = outgoing.send(&connection, &account, &target, confirm).await?;
+            Ok(json!({ "message": message_json(&target, &message) }))
+        },
+        CliCommand::Receive { target } => {
+            let (shared, connection) = load_account(account)?;
+            let account = shared.lock().unwrap().clone().ok_or("Not signed in")?;
+            let messages = match target {
+                Some(target) => receive_from(&connection, &account, &target).await?,
+                None => {
+                    // One session that cannot be read does not keep the others from being read.
+                    let mut messages = vec![];
+                    for target in init_load_user(account.name()) {
+                        match receive_from(&connection, &account, &target).await {
+                            Ok(received) => messages.extend(received),
+                            Err(e) => { warn!("Error receiving messages from {}: {:?}", target, e); }
+                        }
+                    }
+                    messages
+                },
+            };
+            Ok(json!({ "messages": messages }))
+        },
+        CliCommand::Sessions => {
+            let (shared, _) = load_account(account)?;
+            let name = shared.lock().unwrap().as_ref().map(|account| account.name().to_string()).unwrap_or_default();
+            let mut sessions = vec![];
+            for peer in init_load_user(&name) {
+                match SessionKey::load(&peer, shared.clone()) {
+                    Ok(session) => sessions.push(json!({ "peer": peer, "awaiting_reply": session.awaiting_reply() })),
+                    Err(e) => { warn!("Error loading session with {}: {:?}", peer, e); }
+                }
+            }
+            Ok(json!({ "sessions": sessions }))
+        },
+    }
+}
+
+/// Runs `command` as `account` and prints its result as JSON.
+pub fn run(account: Option<&str>, command: CliCommand) -> Result<(), Box<dyn Error>> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    match runtime.block_on(execute(account, command)) {
+        Ok(value) => {
+            println!("{}", value);
+            Ok(())
+        },
+        Err(e) => {
+            println!("{}", json!({ "error": e.to_string() }));
+            Err(e)
+        }
+    }
+}
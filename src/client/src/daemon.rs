@@ -0,0 +1,453 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use log::{info, warn};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use crate::account::Account;
+use crate::chat::{message_json, receive_message, write_message};
+use crate::file::{backup_path, init_load_user, SessionKey};
+use crate::message::Message;
+use crate::push::{self, PushEvent};
+use crate::session::Session;
+use crate::socket::{
+    ack, block_user, get_block_list, get_outgoing_list, get_session, get_session_list, search, sign_in,
+    unblock_user, Connection, MessagePayload, RequestPayload
+};
+use crate::util::{DAEMON_EVENT_BUFFER, DAEMON_INBOX_SIZE, POLL_INTERVAL};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Anything that fails on the way to the server or in the local state.
+const CLIENT_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    /// Requests without an id are notifications and get no response.
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<Box<dyn Error>> for RpcError {
+    fn from(e: Box<dyn Error>) -> Self {
+        Self { code: CLIENT_ERROR, message: e.to_string() }
+    }
+}
+
+#[derive(Deserialize)]
+struct PeerParams {
+    peer: String,
+}
+
+#[derive(Deserialize)]
+struct OptionalPeerParams {
+    peer: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    peer: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    target: String,
+    #[serde(default)]
+    prefix: bool,
+}
+
+#[derive(Deserialize)]
+struct DeclineParams {
+    peer: String,
+    #[serde(default)]
+    notify: bool,
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    // Methods without parameters may be called with none at all.
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| RpcError { code: INVALID_PARAMS, message: e.to_string() })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+/// What the daemon keeps for the signed in account. Every connection goes through it, so the
+/// ratchet of a session only ever moves in one place.
+struct Daemon {
+    account: Account,
+    connection: Connection,
+    shared: Arc<Mutex<Option<Account>>>,
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Messages read from the server that no caller has taken with `receive` yet, at most
+    /// [`DAEMON_INBOX_SIZE`].
+    inbox: Mutex<VecDeque<(String, Message)>>,
+    events: broadcast::Sender<Value>,
+}
+
+impl Daemon {
+    /// Runs `f` on the session with `peer`, loading it from disk the first time.
+    fn with_session<T>(
+        &self,
+        peer: &str,
+        f: impl FnOnce(&mut Session) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !sessions.contains_key(peer) {
+            let session = SessionKey::load(peer, self.shared.clone())
+                .map_err(|e| format!("No session with {}: {}", peer, e))?;
+            sessions.insert(peer.to_string(), session);
+        }
+        f(sessions.get_mut(peer).unwrap())
+    }
+
+    fn deliver(&self, peer: &str, message: Message) {
+        let _ = self.events.send(notification("message", message_json(peer, &message)));
+        let mut inbox = self.inbox.lock().unwrap();
+        if inbox.len() == DAEMON_INBOX_SIZE {
+            warn!("The inbox is full, dropped the oldest message from it");
+            inbox.pop_front();
+        }
+        inbox.push_back((peer.to_string(), message));
+    }
+
+    /// Reads a message from the server. Returns whether it can be acknowledged.
+    fn receive(&self, payload: MessagePayload) -> bool {
+        let sender = payload.sender().to_string();
+        match self.with_session(&sender, |session| receive_message(&self.account, session, payload)) {
+            Ok(message) => {
+                if let Some(message) = message {
+                    self.deliver(&sender, message);
+                }
+                true
+            },
+            Err(e) => {
+                warn!("Error reviving message from {}: {:?}", sender, e);
+                false
+            }
+        }
+    }
+
+    /// Accepts the request of `peer` and makes its session the one in use.
+    async fn accept(&self, peer: &str) -> Result<Option<Message>, Box<dyn Error>> {
+        let (session, first) = RequestPayload::receive(&self.connection, peer.to_string(), self.shared.clone()).await?;
+        self.sessions.lock().unwrap().insert(peer.to_string(), session);
+        if let Some(message) = first.clone() {
+            self.deliver(peer, message);
+        }
+        Ok(first)
+    }
+
+    async fn handle_push(self: Arc<Self>, mut events: UnboundedReceiver<PushEvent>) {
+        while let Some(event) = events.recv().await {
+            match event {
+                PushEvent::Message(payload) => {
+                    let id = payload.id;
+                    if self.receive(payload) {
+                        if let Err(e) = ack(&self.connection, &[id], &[]).await {
+                            warn!("Error acknowledging message: {:?}", e);
+                        }
+                    }
+                },
+                PushEvent::Request { account, timestamp } => {
+                    // A request crossing one this account made is accepted right away, as the
+                    // window does, so both sides settle on one session.
+                    let crossing = self.with_session(&account, |session| Ok(session.awaiting_reply())).unwrap_or(false);
+                    if crossing {
+                        if let Err(e) = self.accept(&account).await.map_err(|e| e.to_string()) {
+                            warn!("Error receiving request: {}", e);
+                        }
+                    } else {
+                        let _ = self.events.send(notification("request", json!({ "account": account, "timestamp": timestamp })));
+                    }
+                },
+                PushEvent::Cancelled { account } => {
+                    let _ = self.events.send(notification("cancelled", json!({ "account": account })));
+                },
+                PushEvent::Declined { account } => {
+                    let _ = self.events.send(notification("declined", json!({ "account": account })));
+                },
+                PushEvent::Receipt { account, count } => {
+                    let _ = self.events.send(notification("receipt", json!({ "account": account, "count": count })));
+                },
+            }
+        }
+    }
+
+    /// Fetches queued messages from every peer with a session while the push connection is down.
+    async fn poll(self: Arc<Self>, connected: Arc<AtomicBool>) {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(POLL_INTERVAL));
+        loop {
+            interval.tick().await;
+            if connected.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            for peer in init_load_user(self.account.name()) {
+                let mut after = None;
+                loop {
+                    let (messages, next) = match MessagePayload::receive(&self.connection, peer.clone(), after).await {
+                        Ok(page) => page,
+                        Err(e) => {
+                            warn!("Error refreshing messages from {}: {:?}", peer, e);
+                            break;
+                        }
+                    };
+
+                    let acked: Vec<i32> = messages.into_iter()
+                        .filter_map(|payload| {
+                            let id = payload.id;
+                            self.receive(payload).then_some(id)
+                        })
+                        .collect();
+                    if !acked.is_empty() {
+                        if let Err(e) = ack(&self.connection, &acked, &[]).await {
+                            warn!("Error acknowledging messages: {:?}", e);
+                        }
+                    }
+
+                    match next {
+                        Some(next) => after = Some(next),
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn call(&self, method: &str, params_value: Value) -> Result<Value, RpcError> {
+        match method {
+            "account" => Ok(json!({ "account": self.account.name() })),
+            "search" => {
+                let SearchParams { target, prefix } = params(params_value)?;
+                Ok(json!({ "results": search(&self.connection, &target, prefix).await? }))
+            },
+            "start" => {
+                let PeerParams { peer } = params(params_value)?;
+                let requested = get_session_list(&self.connection).await?.iter().any(|request| request.account == peer);
+                if requested {
+                    let first = self.accept(&peer).await?;
+                    Ok(json!({ "peer": peer, "accepted": true, "messages": first.iter().map(|m| message_json(&peer, m)).collect::<Vec<_>>() }))
+                } else {
+                    let session = get_session(&self.connection, &peer, self.shared.clone()).await?;
+                    self.sessions.lock().unwrap().insert(peer.clone(), session);
+                    Ok(json!({ "peer": peer, "accepted": false, "messages": [] }))
+                }
+            },
+            "accept" => {
+                let PeerParams { peer } = params(params_value)?;
+                let first = self.accept(&peer).await?;
+                Ok(json!({ "peer": peer, "messages": first.iter().map(|m| message_json(&peer, m)).collect::<Vec<_>>() }))
+            },
+            "decline" => {
+                let DeclineParams { peer, notify } = params(params_value)?;
+                RequestPayload::decline(&self.connection, &peer, notify).await?;
+                Ok(json!({ "peer": peer }))
+            },
+            "cancel" => {
+                let PeerParams { peer } = params(params_value)?;
+                RequestPayload::cancel(&self.connection, &peer).await?;
+                Ok(json!({ "peer": peer }))
+            },
+            "requests" => Ok(json!({ "incoming": get_session_list(&self.connection).await?, "outgoing": get_outgoing_list(&self.connection).await? })),
+            "sessions" => {
+                let sessions: Vec<Value> = init_load_user(self.account.name()).into_iter()
+                    .filter_map(|peer| {
+                        let awaiting = self.with_session(&peer, |session| Ok(session.awaiting_reply())).ok()?;
+                        Some(json!({ "peer": peer, "awaiting_reply": awaiting }))
+                    })
+                    .collect();
+                Ok(json!({ "sessions": sessions }))
+            },
+            "send" => {
+                let SendParams { peer, text } = params(params_value)?;
+                let outgoing = self.with_session(&peer, |session| write_message(&self.account, session, text))?;
+
+                let confirm = || {
+                    let result = self.with_session(&peer, |session| session.confirm_request(self.account.name()));
+                    if let Err(e) = result {
+                        warn!("Error saving the session with {}: {:?}", peer, e);
+                    }
+                };
+                let message = outgoing.send(&self.connection, &self.account, &peer, confirm).await?;
+                Ok(json!({ "message": message_json(&peer, &message) }))
+            },
+            "receive" => {
+                let OptionalPeerParams { peer } = params(params_value)?;
+                let mut inbox = self.inbox.lock().unwrap();
+                let (taken, kept) = std::mem::take(&mut *inbox).into_iter()
+                    .partition(|(sender, _)| peer.as_ref().map_or(true, |peer| peer == sender));
+                *inbox = kept;
+                let messages: Vec<Value> = taken.iter().map(|(sender, message)| message_json(sender, message)).collect();
+                Ok(json!({ "messages": messages }))
+            },
+            "blocked" => Ok(json!({ "blocked": get_block_list(&self.connection).await? })),
+            "block" => {
+                let PeerParams { peer } = params(params_value)?;
+                block_user(&self.connection, &peer).await?;
+                Ok(json!({ "peer": peer }))
+            },
+            "unblock" => {
+                let PeerParams { peer } = params(params_value)?;
+                unblock_user(&self.connection, &peer).await?;
+                Ok(json!({ "peer": peer }))
+            },
+            _ => Err(RpcError { code: METHOD_NOT_FOUND, message: format!("Unknown method {}", method) }),
+        }
+    }
+
+    /// Answers the requests of one connection, one per line, in order. After `subscribe` the
+    /// events of the account are written to it as notifications as well.
+    async fn serve(self: Arc<Self>, stream: UnixStream) {
+        let (reader, mut writer) = stream.into_split();
+        let (output, mut lines_out): (UnboundedSender<Value>, _) = unbounded_channel();
+
+        let write_task = tokio::spawn(async move {
+            while let Some(line) = lines_out.recv().await {
+                if writer.write_all(format!("{}\n", line).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut subscription: Option<tokio::task::JoinHandle<()>> = None;
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let request = match serde_json::from_str::<RpcRequest>(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    let error = json!({ "code": PARSE_ERROR, "message": e.to_string() });
+                    let _ = output.send(json!({ "jsonrpc": "2.0", "id": null, "error": error }));
+                    continue;
+                }
+            };
+
+            let result = if request.method == "subscribe" {
+                if subscription.is_none() {
+                    let mut events = self.events.subscribe();
+                    let output = output.clone();
+                    subscription = Some(tokio::spawn(async move {
+                        loop {
+                            match events.recv().await {
+                                Ok(event) => if output.send(event).is_err() { break; },
+                                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                    warn!("A subscriber missed {} events", missed);
+                                },
+                                Err(broadcast::error::RecvError::Closed) => break,
+                            }
+                        }
+                    }));
+                }
+                Ok(json!({ "subscribed": true }))
+            } else {
+                self.call(&request.method, request.params).await
+            };
+
+            let Some(id) = request.id else { continue; };
+            let response = match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err(e) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": e.code, "message": e.message } }),
+            };
+            if output.send(response).is_err() {
+                break;
+            }
+        }
+
+        if let Some(subscription) = subscription {
+            subscription.abort();
+        }
+        drop(output);
+        let _ = write_task.await;
+    }
+}
+
+/// Where the daemon of `account` listens unless told otherwise.
+pub fn default_socket(account: &str) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(backup_path()?.join(account).join("daemon.sock"))
+}
+
+/// Binds `path`, replacing a socket file left behind by a daemon that is gone. Whoever can
+/// connect acts as the account, so the socket is made in a directory only the owner can enter
+/// and moved to `path` once no one else may connect.
+async fn bind(path: &Path) -> Result<UnixListener, Box<dyn Error>> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(format!("A daemon is already listening on {}", path.display()).into());
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let name = path.file_name().ok_or("The socket path has no file name")?.to_string_lossy();
+    let private = path.with_file_name(format!(".{}.bind", name));
+    let staged = private.join(name.as_ref());
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let listener = UnixListener::bind(&staged)?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::rename(&staged, path)?;
+    std::fs::remove_dir(&private)?;
+    Ok(listener)
+}
+
+/// Signs in as `account`, keeps its push connection open and serves JSON-RPC on `socket` until
+/// interrupted.
+pub async fn run(account: &str, socket: &Path) -> Result<(), Box<dyn Error>> {
+    let account = Account::load(account.to_string())?;
+    let connection = sign_in(&account)?;
+    let listener = bind(socket).await?;
+    info!("Daemon of {} listening on {}", account.name(), socket.display());
+
+    let (events, _) = broadcast::channel(DAEMON_EVENT_BUFFER);
+    let daemon = Arc::new(Daemon {
+        shared: Arc::new(Mutex::new(Some(account.clone()))),
+        account,
+        connection: connection.clone(),
+        sessions: Mutex::new(HashMap::new()),
+        inbox: Mutex::new(VecDeque::new()),
+        events,
+    });
+
+    let connected = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(push::run(connection, sender, Arc::clone(&connected)));
+    tokio::spawn(Arc::clone(&daemon).handle_push(receiver));
+    tokio::spawn(Arc::clone(&daemon).poll(connected));
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => { tokio::spawn(Arc::clone(&daemon).serve(stream)); },
+                Err(e) => { warn!("Error accepting a connection: {:?}", e); }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    info!("Daemon of {} stopped", daemon.account.name());
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
@@ -1,19 +1,49 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
-use std::fs::File;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use chrono::Local;
 use glob::glob;
 use log::info;
+use rand::RngCore;
+use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use crate::account::Account;
+use crate::index::PageIndex;
 use crate::key::{AccountKeys, IdentityKeyPair, OneTimePreKey, SignedPreKeyPair};
-use crate::session::Session;
-use crate::support::{string_to_v32, v32};
+use crate::message::Message;
+use crate::session::{Handshake, Session, SessionState};
+use crate::transparency::LogState;
+use crate::support::{open, passphrase_key, seal, storage_key, string_to_v32, v32};
+use crate::util::{ARCHIVE_ITERATIONS, ARCHIVE_VERSION, HISTORY_PAGE_SIZE, MAX_ARCHIVE_ITERATIONS};
+
+/// The folder set by [`set_backup_path`], which takes the place of `BACKUP_PATH`.
+static BACKUP_FOLDER: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the accounts of this process in `path` instead of `BACKUP_PATH`. Every file helper
+/// resolves the folder on its own, so it is the same for the whole process and set only once.
+pub fn set_backup_path(path: &Path) -> Result<(), Box<dyn Error>> {
+    let folder = BACKUP_FOLDER.get_or_init(|| path.to_path_buf());
+    if folder != path {
+        return Err(format!("The accounts of this process are already kept in {}", folder.display()).into());
+    }
+    Ok(())
+}
+
+/// The folder with the accounts stored locally.
+pub fn backup_path() -> Result<PathBuf, Box<dyn Error>> {
+    match BACKUP_FOLDER.get() {
+        Some(folder) => Ok(folder.clone()),
+        None => Ok(PathBuf::from(std::env::var("BACKUP_PATH")?)),
+    }
+}
 
 pub fn init_load() -> Vec<String> {
     info!("Loading user directories");
-    let pattern = std::env::var("BACKUP_PATH").expect("BACKUP_PATH must be set") + "/*";
+    let pattern = backup_path().expect("BACKUP_PATH must be set").join("*").to_string_lossy().to_string();
 
     let mut users = Vec::new();
 
@@ -33,9 +63,34 @@ pub fn init_load() -> Vec<String> {
     users
 }
 
+/// Whether `name` stays a single folder under the backup folder, for names read from files or
+/// sent by the server.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+/// Writes `contents` next to `path` and renames it over the file, so a page is never left half
+/// written when the process stops in the middle.
+fn replace(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".tmp");
+    fs::write(&staged, contents)?;
+    fs::rename(staged, path)?;
+    Ok(())
+}
+
+/// The folder of the conversation with `target`. Peer names come from the server, so one that
+/// would lead out of the account folder is refused.
+fn peer_folder(account: &str, target: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if !valid_name(target) {
+        return Err(format!("{:?} cannot be used as the name of a peer", target).into());
+    }
+    Ok(backup_path()?.join(account).join(target))
+}
+
 pub fn init_load_user(user: &str) -> Vec<String> {
     info!("Loading user directories");
-    let pattern = std::env::var("BACKUP_PATH").expect("BACKUP_PATH must be set") + user + "/*";
+    let pattern = backup_path().expect("BACKUP_PATH must be set").join(user).join("*").to_string_lossy().to_string();
 
     let mut users = Vec::new();
 
@@ -69,6 +124,9 @@ pub struct LocalKey {
     spk_public: String,
     spk_signature: String,
     opk: Vec<OPKLocal>,
+    /// Missing in keys written before it was kept apart from the identity key.
+    #[serde(default)]
+    storage_key: Option<String>,
 }
 
 impl LocalKey {
@@ -83,9 +141,10 @@ impl LocalKey {
                 key: hex::encode(&k.key),
                 id: k.id,
             }).collect(),
+            storage_key: Some(hex::encode(account.storage_key)),
         };
         
-        let folder_path = Path::new(&std::env::var("BACKUP_PATH")?).join(path);
+        let folder_path = backup_path()?.join(path);
         fs::create_dir_all(&folder_path)?;
         
         let file = File::create(folder_path.join("keys.json"))?;
@@ -96,12 +155,19 @@ impl LocalKey {
     
     pub fn load(account: &str) -> Result<AccountKeys, Box<dyn Error>> {
         let json: LocalKey = serde_json::from_reader(
-            File::open(std::env::var("BACKUP_PATH")? + account + "/keys.json")?
+            File::open(backup_path()?.join(account).join("keys.json"))?
         )?;
         
+        let ik_private = v32(hex::decode(json.ik_private)?)?;
+        // Older accounts sealed their history with a key derived from the identity key. It is
+        // stored as is once the keys are replaced.
+        let storage_key = match json.storage_key {
+            Some(key) => string_to_v32(&key)?,
+            None => storage_key(&ik_private)?,
+        };
         Ok(AccountKeys {
             identity_keypair: IdentityKeyPair {
-                private_key: v32(hex::decode(json.ik_private)?)?,
+                private_key: ik_private,
                 public_key: v32(hex::decode(json.ik_public)?)?,
             },
             signed_prekey: SignedPreKeyPair {
@@ -113,12 +179,15 @@ impl LocalKey {
                 id: k.id,
                 key: string_to_v32(&k.key).unwrap(),
             }).collect(),
+            storage_key,
         })
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SessionKey { 
+    #[serde(default)]
+    pub id: Option<String>,
     pub root_key: String,
     pub recv_key: String,
     pub send_key: String,
@@ -129,11 +198,38 @@ pub struct SessionKey {
     pub ratchet_public: String,
     pub check: bool,
     pub record: Vec<String>,
+    #[serde(default)]
+    pub updated: i64,
+    #[serde(default)]
+    pub handshake: Option<HandshakeKey>,
+    #[serde(default)]
+    pub received: i64,
+    #[serde(default)]
+    pub seq: i64,
+    #[serde(default)]
+    pub ahead: Vec<i64>,
+    #[serde(default)]
+    pub steps: u64,
+}
+
+/// The stored form of a [`Handshake`] the responder has not received yet.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeKey {
+    pub ekp: String,
+    pub opk_id: i32,
+    pub message: Option<String>,
+}
+
+impl HandshakeKey {
+    fn from(handshake: &Handshake) -> Self {
+        Self { ekp: hex::encode(handshake.ekp), opk_id: handshake.opk_id, message: handshake.message.clone() }
+    }
 }
 
 impl SessionKey {
-    pub fn save(session: &Session, account: &str) -> Result<(), Box<dyn Error>> {
-        let json = SessionKey { 
+    fn from(session: &Session) -> Self {
+        SessionKey { 
+            id: Some(hex::encode(&session.id)),
             root_key: hex::encode(&session.root_key),
             recv_key: hex::encode(&session.recv_key),
             send_key: hex::encode(&session.send_key),
@@ -144,64 +240,387 @@ impl SessionKey {
             reverse: session.reverse,
             check: session.check,
             record: session.record.iter().map(|r| hex::encode(r)).collect(),
-        };
-        
-        let folder_path = Path::new(&std::env::var("BACKUP_PATH")?).join(account).join(&session.target);
-        fs::create_dir(&folder_path)?;
+            updated: Local::now().timestamp_millis(),
+            handshake: session.handshake.as_ref().map(HandshakeKey::from),
+            received: session.received,
+            seq: session.seq,
+            ahead: session.ahead.iter().copied().collect(),
+            steps: session.steps,
+        }
+    }
+    
+    fn folder(account: &str, target: &str) -> Result<PathBuf, Box<dyn Error>> {
+        peer_folder(account, target)
+    }
+    
+    /// The active session with a peer is `key.json`, the others are kept in `alternate/` under
+    /// their id until the peer has settled on the active one.
+    fn path(session: &Session, account: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let folder_path = Self::folder(account, &session.target)?;
+        if session.alternate {
+            Ok(folder_path.join("alternate").join(format!("{}.json", hex::encode(session.id))))
+        } else {
+            Ok(folder_path.join("key.json"))
+        }
+    }
+    
+    pub fn save(session: &Session, account: &str) -> Result<(), Box<dyn Error>> {
+        let path = Self::path(session, account)?;
+        fs::create_dir_all(path.parent().ok_or("Invalid session path")?)?;
         
-        let file = File::create(folder_path.join("key.json"))?;
-        serde_json::to_writer_pretty(file, &json)?;
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &Self::from(session))?;
         
         Ok(())
     }
     
     pub fn overload(session: &Session, account: &str) -> Result<(), Box<dyn Error>> {
-        let json = SessionKey { 
-            root_key: hex::encode(&session.root_key),
-            recv_key: hex::encode(&session.recv_key),
-            send_key: hex::encode(&session.send_key),
-            ratchet_private: hex::encode(&session.ratchet_private),
-            last_pub: hex::encode(&session.last_pub),
-            time: session.time,
-            reverse: session.reverse,
-            ratchet_public: hex::encode(&session.ratchet_public),
-            check: session.check,
-            record: session.record.iter().map(|r| hex::encode(r)).collect(),
-        };
-        
-        let folder_path = Path::new(&std::env::var("BACKUP_PATH")?).join(account).join(&session.target);
-        
-        let file = File::create(folder_path.join("key.json"))?;
-        serde_json::to_writer_pretty(file, &json)?;
+        let file = File::create(Self::path(session, account)?)?;
+        serde_json::to_writer_pretty(file, &Self::from(session))?;
         
         Ok(())
     }
     
+    /// Whether `account` has a session with `target` stored.
+    pub fn exists(account: &str, target: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(Self::folder(account, target)?.join("key.json").exists())
+    }
+    
     pub fn load(path: &str, account: Arc<Mutex<Option<Account>>>) -> Result<Session, Box<dyn Error>> {
-        let json: SessionKey = serde_json::from_reader(
-            File::open(
-                std::env::var("BACKUP_PATH")? 
-                    + account.lock().unwrap().as_ref().unwrap().name() 
-                    + "/" + path + "/key.json"
-            )?
-        )?;
+        let name = account.lock().unwrap().as_ref().unwrap().name().to_string();
+        Self::read(&Self::folder(&name, path)?.join("key.json"), path)
+    }
+    
+    /// Loads the sessions kept for `path` besides the active one.
+    pub fn load_alternates(path: &str, account: &str) -> Result<Vec<Session>, Box<dyn Error>> {
+        let pattern = Self::folder(account, path)?.join("alternate").join("*.json");
+        
+        let mut sessions = vec![];
+        for entry in glob(&pattern.to_string_lossy())? {
+            let mut session = Self::read(&entry?, path)?;
+            session.alternate = true;
+            sessions.push(session);
+        }
+        Ok(sessions)
+    }
+    
+    /// Forgets the sessions kept for `path` besides the active one.
+    pub fn remove_alternates(path: &str, account: &str) -> Result<(), Box<dyn Error>> {
+        let folder_path = Self::folder(account, path)?.join("alternate");
+        if folder_path.exists() {
+            fs::remove_dir_all(folder_path)?;
+            info!("Removed alternate sessions with {}", path);
+        }
+        Ok(())
+    }
+    
+    fn read(file: &Path, path: &str) -> Result<Session, Box<dyn Error>> {
+        let json: SessionKey = serde_json::from_reader(File::open(file)?)?;
         let mut record = vec![];
         
         for r in json.record {
             record.push(string_to_v32(&r)?);
         }
-        Ok(Session::load(
-            path,
-            string_to_v32(&json.root_key)?,
-            string_to_v32(&json.recv_key)?,
-            string_to_v32(&json.send_key)?,
-            string_to_v32(&json.ratchet_private)?,
-            string_to_v32(&json.ratchet_public)?,
-            string_to_v32(&json.last_pub)?,
-            json.time,
-            json.reverse,
+        let handshake = match json.handshake {
+            Some(handshake) => Some(Handshake {
+                ekp: string_to_v32(&handshake.ekp)?,
+                opk_id: handshake.opk_id,
+                message: handshake.message,
+            }),
+            None => None,
+        };
+        let id = match json.id {
+            Some(id) => string_to_v32(&id)?,
+            None => [0u8; 32],
+        };
+        Ok(Session::load(path, SessionState {
+            id,
+            root_key: string_to_v32(&json.root_key)?,
+            recv_key: string_to_v32(&json.recv_key)?,
+            send_key: string_to_v32(&json.send_key)?,
+            ratchet_private: string_to_v32(&json.ratchet_private)?,
+            ratchet_public: string_to_v32(&json.ratchet_public)?,
+            last_pub: string_to_v32(&json.last_pub)?,
+            time: json.time,
+            reverse: json.reverse,
             record,
-            json.check,
-        ))
+            check: json.check,
+            handshake,
+            received: json.received,
+            seq: json.seq,
+            ahead: json.ahead.into_iter().collect(),
+            steps: json.steps,
+        }))
+    }
+}
+
+pub struct MessageHistory;
+
+impl MessageHistory {
+    fn folder(account: &str, target: &str) -> Result<PathBuf, Box<dyn Error>> {
+        Ok(peer_folder(account, target)?.join("history"))
+    }
+    
+    /// The lock every write to a conversation takes, since the window and the task reading
+    /// incoming messages both append to it.
+    fn lock(account: &str, target: &str) -> Result<Arc<Mutex<()>>, Box<dyn Error>> {
+        static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+        let folder = Self::folder(account, target)?;
+        Ok(Arc::clone(LOCKS.get_or_init(Default::default).lock().unwrap().entry(folder).or_default()))
+    }
+    
+    pub fn pages(account: &Account, target: &str) -> Result<usize, Box<dyn Error>> {
+        let folder_path = Self::folder(account.name(), target)?;
+        let mut pages = 0;
+        
+        while folder_path.join(format!("{}.bin", pages)).exists() {
+            pages += 1;
+        }
+        
+        Ok(pages)
+    }
+    
+    pub fn load_page(account: &Account, target: &str, page: usize) -> Result<Vec<Message>, Box<dyn Error>> {
+        let path = Self::folder(account.name(), target)?.join(format!("{}.bin", page));
+        if !path.exists() { return Ok(Vec::new()); }
+        
+        let plaintext = open(&account.storage_key(), &fs::read(path)?)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+    
+    fn save_page(account: &Account, target: &str, page: usize, messages: &[Message]) -> Result<(), Box<dyn Error>> {
+        let folder_path = Self::folder(account.name(), target)?;
+        fs::create_dir_all(&folder_path)?;
+        
+        let sealed = seal(&account.storage_key(), &serde_json::to_vec(messages)?)?;
+        replace(&folder_path.join(format!("{}.bin", page)), &sealed)?;
+        
+        Ok(())
+    }
+    
+    /// Loads the newest page of a conversation, returning its page index with the messages.
+    pub fn latest(account: &Account, target: &str) -> Result<(usize, Vec<Message>), Box<dyn Error>> {
+        match Self::pages(account, target)? {
+            0 => Ok((0, Vec::new())),
+            pages => Ok((pages - 1, Self::load_page(account, target, pages - 1)?)),
+        }
+    }
+    
+    /// Loads every page from `page` up to the newest one, so a chat can be opened at an older message.
+    pub fn since(account: &Account, target: &str, page: usize) -> Result<(usize, Vec<Message>), Box<dyn Error>> {
+        let pages = Self::pages(account, target)?;
+        let page = page.min(pages.saturating_sub(1));
+        let mut messages = vec![];
+        
+        for i in page..pages {
+            messages.extend(Self::load_page(account, target, i)?);
+        }
+        
+        Ok((page, messages))
+    }
+    
+    /// Appends a message to the conversation and assigns its local id. Ids start at 1 and map
+    /// directly onto the page that holds them, so `(id - 1) / HISTORY_PAGE_SIZE` is its page.
+    pub fn append(account: &Account, target: &str, message: Message) -> Result<Message, Box<dyn Error>> {
+        let mut stored = Self::extend(account, target, vec![message])?;
+        Ok(stored.remove(0))
+    }
+    
+    /// Appends several messages at once, writing each touched page and the search index only once.
+    pub fn extend(account: &Account, target: &str, messages: Vec<Message>) -> Result<Vec<Message>, Box<dyn Error>> {
+        let lock = Self::lock(account.name(), target)?;
+        let _guard = lock.lock().unwrap();
+        Self::write(account, target, messages)
+    }
+    
+    /// Merges `messages` into the conversation, skipping the ones it already holds. Ids point at
+    /// pages and may be kept by search hits or unsent messages, so the messages already stored stay
+    /// where they are and the new ones follow them in the order they were written.
+    /// Returns how many messages were added.
+    pub fn merge(account: &Account, target: &str, mut messages: Vec<Message>) -> Result<usize, Box<dyn Error>> {
+        let lock = Self::lock(account.name(), target)?;
+        let _guard = lock.lock().unwrap();
+        
+        let (_, stored) = Self::since(account, target, 0)?;
+        let mut known: HashSet<(i64, bool, String)> = stored.iter()
+            .map(|message| (message.timestamp, message.sender, message.text.clone()))
+            .collect();
+        messages.retain(|message| known.insert((message.timestamp, message.sender, message.text.clone())));
+        messages.sort_by_key(|message| message.timestamp);
+        
+        Ok(Self::write(account, target, messages)?.len())
+    }
+    
+    fn write(account: &Account, target: &str, messages: Vec<Message>) -> Result<Vec<Message>, Box<dyn Error>> {
+        let (mut page, mut current) = Self::latest(account, target)?;
+        let mut stored = vec![];
+        
+        for mut message in messages {
+            if current.len() >= HISTORY_PAGE_SIZE {
+                Self::save_page(account, target, page, &current)?;
+                LocalIndex::save(account, target, page, &PageIndex::build(&current))?;
+                page += 1;
+                current.clear();
+            }
+            
+            message.id = (page * HISTORY_PAGE_SIZE + current.len() + 1) as u64;
+            current.push(message.clone());
+            stored.push(message);
+        }
+        
+        if !stored.is_empty() {
+            Self::save_page(account, target, page, &current)?;
+            LocalIndex::save(account, target, page, &PageIndex::build(&current))?;
+        }
+        
+        Ok(stored)
+    }
+    
+    /// The search index of one page, built from the page if it has none yet.
+    pub fn page_index(account: &Account, target: &str, page: usize) -> Result<PageIndex, Box<dyn Error>> {
+        let lock = Self::lock(account.name(), target)?;
+        let _guard = lock.lock().unwrap();
+        if let Some(index) = LocalIndex::load(account, target, page)? {
+            return Ok(index);
+        }
+        
+        let index = PageIndex::build(&Self::load_page(account, target, page)?);
+        LocalIndex::save(account, target, page, &index)?;
+        Ok(index)
+    }
+    
+    /// Marks the message with `id` as accepted by the server.
+    pub fn settle(account: &Account, target: &str, id: u64) -> Result<(), Box<dyn Error>> {
+        if id == 0 { return Ok(()); }
+        
+        let lock = Self::lock(account.name(), target)?;
+        let _guard = lock.lock().unwrap();
+        let page = (id as usize - 1) / HISTORY_PAGE_SIZE;
+        let mut messages = Self::load_page(account, target, page)?;
+        
+        if let Some(message) = messages.iter_mut().find(|message| message.id == id) {
+            message.pending = false;
+            Self::save_page(account, target, page, &messages)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sealed search index of one history page, kept next to the page.
+pub struct LocalIndex;
+
+impl LocalIndex {
+    fn path(account: &str, target: &str, page: usize) -> Result<PathBuf, Box<dyn Error>> {
+        Ok(MessageHistory::folder(account, target)?.join(format!("{}.idx", page)))
+    }
+    
+    pub fn load(account: &Account, target: &str, page: usize) -> Result<Option<PageIndex>, Box<dyn Error>> {
+        let path = Self::path(account.name(), target, page)?;
+        if !path.exists() { return Ok(None); }
+        
+        let plaintext = open(&account.storage_key(), &fs::read(path)?)?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+    
+    pub fn save(account: &Account, target: &str, page: usize, index: &PageIndex) -> Result<(), Box<dyn Error>> {
+        let sealed = seal(&account.storage_key(), &serde_json::to_vec(index)?)?;
+        replace(&Self::path(account.name(), target, page)?, &sealed)?;
+        
+        Ok(())
+    }
+}
+
+/// Held while a process acts as an account. Its sessions are files that every message moves on,
+/// so a second process reading and writing them at the same time would fork the ratchets.
+pub struct AccountLock {
+    _file: File,
+}
+
+impl AccountLock {
+    pub fn acquire(account: &str) -> Result<Self, Box<dyn Error>> {
+        let folder = backup_path()?.join(account);
+        fs::create_dir_all(&folder)?;
+        let file = OpenOptions::new().create(true).truncate(false).write(true).mode(0o600).open(folder.join("account.lock"))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(format!("{} is in use by another process, like its daemon or the app", account).into()),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+/// The key transparency state of an account. Tree heads are public, so it is stored in the clear.
+pub struct LocalLog;
+
+impl LocalLog {
+    fn path(account: &str) -> Result<PathBuf, Box<dyn Error>> {
+        Ok(backup_path()?.join(account).join("transparency.json"))
+    }
+    
+    pub fn load(account: &str) -> Result<LogState, Box<dyn Error>> {
+        let path = Self::path(account)?;
+        if !path.exists() { return Ok(LogState::default()); }
+        
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+    
+    pub fn save(account: &str, state: &LogState) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer_pretty(File::create(Self::path(account)?)?, state)?;
+        Ok(())
+    }
+}
+
+/// A file sealed with a key derived from a passphrase, so it can be opened on another installation
+/// that does not share the identity key. `kind` tells transcript and account archives apart.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocalArchive {
+    kind: String,
+    version: u32,
+    salt: String,
+    iterations: u32,
+    data: String,
+}
+
+impl LocalArchive {
+    pub fn seal(kind: &str, passphrase: &str, plaintext: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        
+        let key = passphrase_key(passphrase, &salt, ARCHIVE_ITERATIONS)?;
+        
+        Ok(Self {
+            kind: kind.to_string(),
+            version: ARCHIVE_VERSION,
+            salt: hex::encode(salt),
+            iterations: ARCHIVE_ITERATIONS,
+            data: hex::encode(seal(&key, plaintext)?),
+        })
+    }
+    
+    pub fn open(&self, kind: &str, passphrase: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        if self.kind != kind {
+            return Err(format!("Expected a {} archive, found {}", kind, self.kind).into());
+        }
+        if self.version > ARCHIVE_VERSION {
+            return Err(format!("Unsupported archive version {}", self.version).into());
+        }
+        if self.iterations > MAX_ARCHIVE_ITERATIONS {
+            return Err(format!("Archive asks for {} key derivation iterations, at most {} are allowed", self.iterations, MAX_ARCHIVE_ITERATIONS).into());
+        }
+        
+        let key = passphrase_key(passphrase, &hex::decode(&self.salt)?, self.iterations)?;
+        open(&key, &hex::decode(&self.data)?).map_err(|_| "Wrong passphrase or corrupted archive".into())
+    }
+    
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        
+        Ok(())
+    }
+    
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
     }
 }
@@ -0,0 +1,128 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use serde::{Deserialize, Serialize};
+use crate::account::Account;
+use crate::file::{init_load_user, MessageHistory};
+use crate::message::Message;
+use crate::util::{HISTORY_PAGE_SIZE, MAX_SEARCH_RESULTS};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub target: String,
+    pub id: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub target: String,
+    pub message: Message,
+}
+
+/// The terms of one history page, stored next to it so writing a message only rewrites the
+/// index of the page it lands on.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PageIndex {
+    terms: HashMap<String, Vec<u64>>,
+}
+
+impl PageIndex {
+    pub fn build(messages: &[Message]) -> Self {
+        let mut index = Self::default();
+        for message in messages {
+            for token in tokenize(&message.text) {
+                index.terms.entry(token).or_default().push(message.id);
+            }
+        }
+        index
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    terms: HashMap<String, Vec<IndexEntry>>,
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .filter(|t| seen.insert(t.clone()))
+        .collect()
+}
+
+impl SearchIndex {
+    /// Collects the index of every history page of the account. Pages without one, such as
+    /// restored pages, are indexed on the way.
+    pub fn load(account: &Account) -> Result<Self, Box<dyn Error>> {
+        let mut index = Self::default();
+
+        for target in init_load_user(account.name()) {
+            for page in 0..MessageHistory::pages(account, &target)? {
+                for (term, ids) in MessageHistory::page_index(account, &target, page)?.terms {
+                    let entries = index.terms.entry(term).or_default();
+                    entries.extend(ids.into_iter().map(|id| IndexEntry { target: target.clone(), id }));
+                }
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Returns the entries containing every query term. The last term also matches as a prefix,
+    /// so results narrow down while the query is still being typed.
+    fn lookup(&self, query: &str, target: Option<&str>) -> Vec<IndexEntry> {
+        let tokens = tokenize(query);
+        let mut result: Option<Vec<IndexEntry>> = None;
+
+        for (i, token) in tokens.iter().enumerate() {
+            let mut matches: Vec<IndexEntry> = if i + 1 == tokens.len() {
+                self.terms.iter()
+                    .filter(|(term, _)| term.starts_with(token.as_str()))
+                    .flat_map(|(_, entries)| entries.iter().cloned())
+                    .collect()
+            } else {
+                self.terms.get(token).cloned().unwrap_or_default()
+            };
+
+            if let Some(target) = target {
+                matches.retain(|e| e.target == target);
+            }
+
+            result = Some(match result {
+                Some(previous) => {
+                    let matches: HashSet<IndexEntry> = matches.into_iter().collect();
+                    previous.into_iter().filter(|e| matches.contains(e)).collect()
+                },
+                None => matches,
+            });
+        }
+
+        let mut seen = HashSet::new();
+        let mut result = result.unwrap_or_default();
+        result.retain(|e| seen.insert((e.target.clone(), e.id)));
+        result
+    }
+
+    pub fn search(&self, account: &Account, query: &str, target: Option<&str>) -> Result<Vec<SearchHit>, Box<dyn Error>> {
+        let mut pages: HashMap<(String, usize), Vec<Message>> = HashMap::new();
+        let mut hits = vec![];
+
+        for entry in self.lookup(query, target) {
+            let page = (entry.id as usize).saturating_sub(1) / HISTORY_PAGE_SIZE;
+            let key = (entry.target.clone(), page);
+
+            if !pages.contains_key(&key) {
+                pages.insert(key.clone(), MessageHistory::load_page(account, &entry.target, page)?);
+            }
+
+            if let Some(message) = pages[&key].iter().find(|m| m.id == entry.id) {
+                hits.push(SearchHit { target: entry.target, message: message.clone() });
+            }
+        }
+
+        hits.sort_by(|a, b| b.message.timestamp.cmp(&a.message.timestamp));
+        hits.truncate(MAX_SEARCH_RESULTS);
+        Ok(hits)
+    }
+}
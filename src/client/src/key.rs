@@ -1,14 +1,19 @@
 use std::error::Error;
 use serde::{Deserialize, Serialize};
 use crate::file::LocalKey;
-use crate::socket::UploadPayload;
-use crate::support::X25519;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use crate::socket::{KeyUpdate, UploadPayload};
+use crate::support::{xeddsa_sign, X25519};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AccountKeys {
     pub identity_keypair: IdentityKeyPair,
     pub signed_prekey: SignedPreKeyPair,
     pub one_time_prekeys: Vec<OneTimePreKey>,
+    /// Seals the history and its index. It is not derived from the identity key, so history
+    /// stays readable when the keys are replaced.
+    pub storage_key: [u8; 32],
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -32,7 +37,10 @@ pub struct OneTimePreKey {
 
 
 impl AccountKeys {
-    pub async fn new(account: &str) -> Result<Self, Box<dyn Error>> {
+    /// Generates and registers a fresh key set, returning it with the recovery code that can
+    /// replace it later. The keys are only stored locally once the server has accepted them.
+    /// `storage_key` is kept from the keys being replaced, a new account gets a random one.
+    pub async fn new(server: &str, account: &str, update: KeyUpdate<'_>, storage_key: Option<[u8; 32]>) -> Result<(Self, String), Box<dyn Error>> {
         let temp = X25519::rand_key();
         
         let identity_keypair = IdentityKeyPair {
@@ -51,26 +59,36 @@ impl AccountKeys {
         }
         
         let key = AccountKeys {
-            signed_prekey: Self::generate_signed_prekey()?,
+            signed_prekey: Self::generate_signed_prekey(&identity_keypair)?,
             one_time_prekeys: opk,
             identity_keypair,
+            storage_key: storage_key.unwrap_or_else(|| {
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }),
         };
         
+        let mut recovery = [0u8; 16];
+        OsRng.fill_bytes(&mut recovery);
+        let recovery = hex::encode(recovery);
+        
+        UploadPayload::new(server, &key, &account, opk_pub, &recovery, update).await?;
         LocalKey::save(&key, &account)?;
-        UploadPayload::new(&key, &account, opk_pub).await?;
         
-        Ok(key)
+        Ok((key, recovery))
     }
     
+    /// A fresh signed prekey, signed by the identity key with XEdDSA.
     fn generate_signed_prekey(
-        // identity_keypair: &Ed25519KeyPair
+        identity_keypair: &IdentityKeyPair
     ) -> Result<SignedPreKeyPair, Box<dyn Error>> {
         let keypair = X25519::rand_key();
 
         Ok(SignedPreKeyPair {
             private_key: keypair.private,
             public_key: keypair.public,
-            signature: [1u8; 32].to_vec(),
+            signature: xeddsa_sign(&identity_keypair.private_key, &keypair.public).to_vec(),
         })
     }
 
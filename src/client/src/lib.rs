@@ -7,14 +7,32 @@ mod socket;
 mod session;
 mod support;
 mod util;
+mod index;
+mod transcript;
+mod backup;
+mod push;
+mod transparency;
+mod chat;
+mod cli;
+mod daemon;
+mod sdk;
 
+use std::error::Error;
+use std::path::Path;
 use fern::Dispatch;
 use chrono::Local;
 
 use eframe;
 use crate::app::AppState;
+use crate::backup::AccountBackup;
 
-fn setup_logger() -> Result<(), fern::InitError> {
+pub use crate::cli::CliCommand;
+pub use crate::file::set_backup_path;
+pub use crate::message::Message;
+pub use crate::sdk::{ClientConfig, E2eeClient, Event};
+pub use crate::socket::PendingRequest;
+
+fn setup_logger(level: log::LevelFilter, output: impl Into<fern::Output>) -> Result<(), fern::InitError> {
     Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -25,18 +43,54 @@ fn setup_logger() -> Result<(), fern::InitError> {
                 message
             ))
         })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
+        .level(level)
+        .chain(output)
         .apply()?;
     Ok(())
 }
 
 pub fn start() -> eframe::Result<()> {
-    setup_logger().expect("Failed to setup logger");
+    setup_logger(log::LevelFilter::Info, std::io::stdout()).expect("Failed to setup logger");
     
     eframe::run_native(
         "End-to-End Encrypted Chat", 
         eframe::NativeOptions::default(), 
         Box::new(|_| Ok(Box::new(AppState::new())))
     )
-}
\ No newline at end of file
+}
+
+pub fn backup(account: &str, output: &Path, passphrase: &str, history: bool) -> Result<(), Box<dyn Error>> {
+    setup_logger(log::LevelFilter::Info, std::io::stdout())?;
+    AccountBackup::create(account, history)?.save(output, passphrase)
+}
+
+pub fn restore(input: &Path, passphrase: &str) -> Result<(), Box<dyn Error>> {
+    setup_logger(log::LevelFilter::Info, std::io::stdout())?;
+    let report = AccountBackup::load(input, passphrase)?.restore()?;
+    
+    for peer in &report.skipped {
+        println!("Skipped {}: the local session is newer than the backup", peer);
+    }
+    for peer in &report.conflicts {
+        println!("Skipped {}: another session with it is stored locally, remove its key.json to restore the backup's", peer);
+    }
+    println!("Restored {} sessions", report.restored.len());
+    Ok(())
+}
+
+/// Runs one command of the headless client as `account`. Only warnings are logged, to stderr,
+/// so stdout carries nothing but the JSON result.
+pub fn cli(account: Option<&str>, command: CliCommand) -> Result<(), Box<dyn Error>> {
+    setup_logger(log::LevelFilter::Warn, std::io::stderr())?;
+    cli::run(account, command)
+}
+
+/// Runs the daemon of `account` on `socket`, or on `daemon.sock` in the account folder.
+pub fn daemon(account: &str, socket: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    setup_logger(log::LevelFilter::Info, std::io::stdout())?;
+    let socket = match socket {
+        Some(socket) => socket.to_path_buf(),
+        None => daemon::default_socket(account)?,
+    };
+    tokio::runtime::Runtime::new()?.block_on(daemon::run(account, &socket))
+}
@@ -1,23 +1,77 @@
 use std::fmt;
-use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono::{Local, TimeZone};
 use serde::{Deserialize, Serialize};
+use crate::util::MAX_TIMESTAMP_SKEW;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    #[serde(default)]
+    pub id: u64,
     pub sender: bool,
+    /// When the sender wrote the message, as sealed inside the ciphertext.
     pub timestamp: i64,
     pub text: String,
+    /// When the server received a message from the peer. Unset for messages this account sent.
+    #[serde(default)]
+    pub received: Option<i64>,
+    /// Set while a message this account wrote has not reached the server.
+    #[serde(default)]
+    pub pending: bool,
+}
+
+/// What is encrypted for each message, so the server can neither read nor change when it was written.
+#[derive(Serialize, Deserialize)]
+struct Content {
+    text: String,
+    timestamp: i64,
 }
 
 impl Message {
     pub fn new(text: String) -> Self {
-        Self { sender: true, timestamp: Local::now().timestamp(), text }
+        Self { id: 0, sender: true, timestamp: Local::now().timestamp(), text, received: None, pending: true }
+    }
+    
+    /// The plaintext to encrypt for this message.
+    pub fn seal(&self) -> Vec<u8> {
+        serde_json::to_vec(&Content { text: self.text.clone(), timestamp: self.timestamp }).unwrap()
+    }
+    
+    /// Reads a decrypted message the server received at `received`. Messages from clients that
+    /// sent the bare text carry no time of their own and get the time of receipt.
+    pub fn open(plaintext: String, received: i64) -> Self {
+        let (text, timestamp) = match serde_json::from_str::<Content>(&plaintext) {
+            Ok(content) => (content.text, content.timestamp),
+            Err(_) => (plaintext, received),
+        };
+        Self { id: 0, sender: false, timestamp, text, received: Some(received), pending: false }
     }
     
     pub fn timestamp(&self) -> String {
-        let naive_datetime = DateTime::from_timestamp(self.timestamp, 0);
-        let datetime: DateTime<Utc> = Utc.from_utc_datetime(&naive_datetime.expect("REASON").naive_utc());
-        datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+        format_timestamp(self.timestamp)
+    }
+    
+    /// Whether the time the sender gives is further from the time of receipt than clocks drift.
+    pub fn skewed(&self) -> bool {
+        self.received.is_some_and(|received| (received - self.timestamp).abs() > MAX_TIMESTAMP_SKEW)
+    }
+}
+
+/// Formats `timestamp` in the local time zone.
+pub fn format_timestamp(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(datetime) => datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// How long ago `timestamp` was, in the largest whole unit.
+pub fn format_age(timestamp: i64) -> String {
+    let seconds = (Local::now().timestamp() - timestamp).max(0);
+    match seconds {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
     }
 }
 
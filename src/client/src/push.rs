@@ -0,0 +1,92 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use futures_util::StreamExt;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use crate::socket::{Connection, MessagePayload};
+use crate::util::{PUSH_MAX_BACKOFF, PUSH_MIN_BACKOFF};
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PushEvent {
+    Message(MessagePayload),
+    Request { account: String, timestamp: i64 },
+    Cancelled { account: String },
+    Declined { account: String },
+    Receipt { account: String, count: usize },
+}
+
+/// Keeps one push connection open for the account of `connection`, reconnecting with exponential
+/// backoff. `connected` is cleared while the connection is down so callers can poll instead.
+/// Returns, closing the connection, as soon as nobody listens to `events` any more.
+pub async fn run(connection: Connection, events: UnboundedSender<PushEvent>, connected: Arc<AtomicBool>) {
+    let mut backoff = PUSH_MIN_BACKOFF;
+    let mut failed = false;
+
+    while !events.is_closed() {
+        // A failed handshake may be an expired token, so the next attempt logs in again.
+        match listen(&connection, &events, &connected, failed).await {
+            Ok(()) => {
+                info!("Push connection closed");
+                backoff = PUSH_MIN_BACKOFF;
+                failed = false;
+            },
+            Err(e) => {
+                warn!("Push connection failed: {:?}", e);
+                failed = true;
+            }
+        }
+        connected.store(false, Ordering::Relaxed);
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(backoff)) => {},
+            _ = events.closed() => break,
+        }
+        backoff = (backoff * 2).min(PUSH_MAX_BACKOFF);
+    }
+}
+
+async fn listen(
+    connection: &Connection,
+    events: &UnboundedSender<PushEvent>, 
+    connected: &AtomicBool, 
+    refresh: bool
+) -> Result<(), Box<dyn Error>> {
+    let url = connection.server().replacen("http", "ws", 1) + "/ws/";
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(AUTHORIZATION, format!("Bearer {}", connection.token(refresh).await?).parse()?);
+
+    let (mut stream, _) = connect_async(request).await?;
+    connected.store(true, Ordering::Relaxed);
+    info!("Push connection established");
+
+    loop {
+        // Waiting for the next push alone would keep the connection open until one arrives.
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = events.closed() => break,
+        };
+        let Some(message) = message else { break; };
+        match message? {
+            Message::Text(text) => match serde_json::from_str::<PushEvent>(&text) {
+                Ok(event) => {
+                    if events.send(event).is_err() { break; }
+                },
+                Err(e) => {
+                    warn!("Unknown push event: {:?}", e);
+                }
+            },
+            Message::Close(_) => break,
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use log::warn;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use crate::account::Account;
+use crate::chat::{receive_message, write_message};
+use crate::file::{init_load, init_load_user, SessionKey};
+use crate::message::Message;
+use crate::push::{self, PushEvent};
+use crate::session::Session;
+use crate::socket::{
+    ack, block_user, get_block_list, get_outgoing_list, get_session, get_session_list, search, unblock_user,
+    Connection, MessagePayload, PendingRequest, RequestPayload
+};
+use crate::util::POLL_INTERVAL;
+
+/// Something that happened for the signed in account while [`E2eeClient::events`] listens.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A message from `peer`, already decrypted, stored in the history and acknowledged.
+    Message { peer: String, message: Message },
+    /// `peer` asked for a session. Accept it with [`E2eeClient::accept_request`].
+    Request { peer: String, timestamp: i64 },
+    /// `peer` withdrew its session request.
+    Cancelled { peer: String },
+    /// `peer` declined the session request of this account.
+    Declined { peer: String },
+    /// `peer` read `count` messages of this account.
+    Receipt { peer: String, count: usize },
+}
+
+/// The server an [`E2eeClient`] talks to. The folder accounts are kept in is not part of it,
+/// since it belongs to the whole process, see [`crate::set_backup_path`].
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub server: String,
+}
+
+impl ClientConfig {
+    pub fn new(server: impl Into<String>) -> Self {
+        Self { server: server.into() }
+    }
+
+    /// The configuration the rest of the client uses, from `SERVER_URL`.
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(Self::new(std::env::var("SERVER_URL")?))
+    }
+}
+
+struct Inner {
+    account: Account,
+    connection: Connection,
+    shared: Arc<Mutex<Option<Account>>>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+/// An account signed in to the server, for programs that chat without the window. The server
+/// comes from a [`ClientConfig`] or `SERVER_URL`, the folder with the keys from `BACKUP_PATH`
+/// unless the process chose another with [`crate::set_backup_path`].
+///
+/// Clones share the account and its sessions, so one client can be handed to several tasks.
+/// Each client authenticates as its own account, so one process can run several.
+#[derive(Clone)]
+pub struct E2eeClient {
+    inner: Arc<Inner>,
+}
+
+impl E2eeClient {
+    fn new(config: &ClientConfig, account: Account) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            inner: Arc::new(Inner {
+                connection: Connection::new(&config.server, &account)?,
+                shared: Arc::new(Mutex::new(Some(account.clone()))),
+                account,
+                sessions: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+
+    /// Registers `account` with the server and signs in. Its recovery code is only available
+    /// from [`E2eeClient::recovery_code`] of the returned client.
+    pub async fn create(account: &str) -> Result<Self, Box<dyn Error>> {
+        Self::create_with(&ClientConfig::from_env()?, account).await
+    }
+
+    /// Like [`E2eeClient::create`], with the server of `config`.
+    pub async fn create_with(config: &ClientConfig, account: &str) -> Result<Self, Box<dyn Error>> {
+        Self::new(config, Account::new(&config.server, account.to_string()).await?)
+    }
+
+    /// Signs in with the keys of `account` stored locally.
+    pub fn load(account: &str) -> Result<Self, Box<dyn Error>> {
+        Self::load_with(&ClientConfig::from_env()?, account)
+    }
+
+    /// Like [`E2eeClient::load`], with the server of `config`.
+    pub fn load_with(config: &ClientConfig, account: &str) -> Result<Self, Box<dyn Error>> {
+        Self::new(config, Account::load(account.to_string())?)
+    }
+
+    /// The accounts stored locally.
+    pub fn accounts() -> Vec<String> {
+        init_load()
+    }
+
+    pub fn name(&self) -> &str {
+        self.inner.account.name()
+    }
+
+    /// The recovery code of an account created by this client.
+    pub fn recovery_code(&self) -> Option<&str> {
+        self.inner.account.recovery_code()
+    }
+
+    /// Runs `f` on the session with `peer`, loading it from disk the first time.
+    fn with_session<T>(
+        &self,
+        peer: &str,
+        f: impl FnOnce(&mut Session) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut sessions = self.inner.sessions.lock().unwrap();
+        if !sessions.contains_key(peer) {
+            let session = SessionKey::load(peer, self.inner.shared.clone())
+                .map_err(|e| format!("No session with {}: {}", peer, e))?;
+            sessions.insert(peer.to_string(), session);
+        }
+        f(sessions.get_mut(peer).unwrap())
+    }
+
+    /// Finds `target` by its exact name, or discoverable accounts starting with it.
+    pub async fn search(&self, target: &str, prefix: bool) -> Result<Vec<String>, Box<dyn Error>> {
+        search(&self.inner.connection, target, prefix).await
+    }
+
+    /// Starts a session with `peer`, or accepts its request if it sent one. Returns the message
+    /// an accepted request carried.
+    pub async fn start_session(&self, peer: &str) -> Result<Option<Message>, Box<dyn Error>> {
+        let requested = get_session_list(&self.inner.connection).await?.iter().any(|request| request.account == peer);
+        if requested {
+            return self.accept_request(peer).await;
+        }
+
+        let session = get_session(&self.inner.connection, peer, self.inner.shared.clone()).await?;
+        self.inner.sessions.lock().unwrap().insert(peer.to_string(), session);
+        Ok(None)
+    }
+
+    /// The session requests waiting for this account.
+    pub async fn requests(&self) -> Result<Vec<PendingRequest>, Box<dyn Error>> {
+        get_session_list(&self.inner.connection).await
+    }
+
+    /// The session requests of this account nobody accepted yet.
+    pub async fn outgoing_requests(&self) -> Result<Vec<PendingRequest>, Box<dyn Error>> {
+        get_outgoing_list(&self.inner.connection).await
+    }
+
+    /// Accepts the session request of `peer` and returns the message it carried.
+    pub async fn accept_request(&self, peer: &str) -> Result<Option<Message>, Box<dyn Error>> {
+        let (session, first) = RequestPayload::receive(&self.inner.connection, peer.to_string(), self.inner.shared.clone()).await?;
+        self.inner.sessions.lock().unwrap().insert(peer.to_string(), session);
+        Ok(first)
+    }
+
+    /// Declines the session request of `peer`, telling it so if `notify` is set.
+    pub async fn decline_request(&self, peer: &str, notify: bool) -> Result<(), Box<dyn Error>> {
+        RequestPayload::decline(&self.inner.connection, peer, notify).await
+    }
+
+    /// Withdraws the session request this account sent to `peer`.
+    pub async fn cancel_request(&self, peer: &str) -> Result<(), Box<dyn Error>> {
+        RequestPayload::cancel(&self.inner.connection, peer).await
+    }
+
+    /// The peers this account has a session with.
+    pub fn sessions(&self) -> Vec<String> {
+        init_load_user(self.name())
+    }
+
+    /// Whether the session with `peer` was started here and the peer has not answered yet.
+    pub fn awaiting_reply(&self, peer: &str) -> Result<bool, Box<dyn Error>> {
+        self.with_session(peer, |session| Ok(session.awaiting_reply()))
+    }
+
+    /// Encrypts `text` for `peer`, sends it and returns it as stored in the history.
+    pub async fn send(&self, peer: &str, text: &str) -> Result<Message, Box<dyn Error>> {
+        let outgoing = self.with_session(peer, |session| write_message(&self.inner.account, session, text.to_string()))?;
+
+        let confirm = || {
+            let result = self.with_session(peer, |session| session.confirm_request(self.name()));
+            if let Err(e) = result {
+                warn!("Error saving the session with {}: {:?}", peer, e);
+            }
+        };
+        outgoing.send(&self.inner.connection, &self.inner.account, peer, confirm).await
+    }
+
+    /// Reads a message from the server. Returns whether it can be acknowledged.
+    fn read(&self, payload: MessagePayload) -> (bool, Option<Message>) {
+        let sender = payload.sender().to_string();
+        match self.with_session(&sender, |session| receive_message(&self.inner.account, session, payload)) {
+            Ok(message) => (true, message),
+            Err(e) => {
+                warn!("Error reviving message from {}: {:?}", sender, e);
+                (false, None)
+            }
+        }
+    }
+
+    /// Fetches, decrypts and acknowledges every message queued from `peer`. Messages that cannot
+    /// be decrypted are left on the server.
+    pub async fn receive(&self, peer: &str) -> Result<Vec<Message>, Box<dyn Error>> {
+        self.with_session(peer, |_| Ok(()))?;
+        let mut messages = vec![];
+        let mut after = None;
+
+        loop {
+            let (page, next) = MessagePayload::receive(&self.inner.connection, peer.to_string(), after).await?;
+            let mut acked = vec![];
+            for payload in page {
+                let id = payload.id;
+                let (read, message) = self.read(payload);
+                if read {
+                    acked.push(id);
+                }
+                messages.extend(message);
+            }
+            if !acked.is_empty() {
+                ack(&self.inner.connection, &acked, &[]).await?;
+            }
+
+            match next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        Ok(messages)
+    }
+
+    pub async fn blocked(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        get_block_list(&self.inner.connection).await
+    }
+
+    pub async fn block(&self, peer: &str) -> Result<(), Box<dyn Error>> {
+        block_user(&self.inner.connection, peer).await
+    }
+
+    pub async fn unblock(&self, peer: &str) -> Result<(), Box<dyn Error>> {
+        unblock_user(&self.inner.connection, peer).await
+    }
+
+    /// Opens the push connection of the account and returns what arrives on it. Messages are
+    /// read as they come, and fetched every few seconds while the connection is down. Requests
+    /// crossing one this account sent are accepted right away, so both sides settle on one
+    /// session. The push connection is closed and polling stops as soon as the receiver is dropped.
+    pub fn events(&self) -> UnboundedReceiver<Event> {
+        let (events, receiver) = unbounded_channel();
+        let connected = Arc::new(AtomicBool::new(false));
+        let (push_events, push_receiver) = unbounded_channel();
+
+        tokio::spawn(push::run(self.inner.connection.clone(), push_events, Arc::clone(&connected)));
+        tokio::spawn(self.clone().handle_push(push_receiver, events.clone()));
+        tokio::spawn(self.clone().poll(connected, events));
+        receiver
+    }
+
+    async fn handle_push(self, mut push_events: UnboundedReceiver<PushEvent>, events: UnboundedSender<Event>) {
+        loop {
+            // Returning drops `push_events`, which closes the push connection.
+            let event = tokio::select! {
+                event = push_events.recv() => event,
+                _ = events.closed() => break,
+            };
+            let Some(event) = event else { break; };
+            let event = match event {
+                PushEvent::Message(payload) => {
+                    let id = payload.id;
+                    let peer = payload.sender().to_string();
+                    let (read, message) = self.read(payload);
+                    if read {
+                        if let Err(e) = ack(&self.inner.connection, &[id], &[]).await.map_err(|e| e.to_string()) {
+                            warn!("Error acknowledging message: {}", e);
+                        }
+                    }
+                    match message {
+                        Some(message) => Event::Message { peer, message },
+                        None => continue,
+                    }
+                },
+                PushEvent::Request { account, timestamp } => {
+                    if !self.awaiting_reply(&account).unwrap_or(false) {
+                        Event::Request { peer: account, timestamp }
+                    } else {
+                        match self.accept_request(&account).await.map_err(|e| e.to_string()) {
+                            Ok(Some(message)) => Event::Message { peer: account, message },
+                            Ok(None) => continue,
+                            Err(e) => {
+                                warn!("Error receiving request: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                },
+                PushEvent::Cancelled { account } => Event::Cancelled { peer: account },
+                PushEvent::Declined { account } => Event::Declined { peer: account },
+                PushEvent::Receipt { account, count } => Event::Receipt { peer: account, count },
+            };
+
+            if events.send(event).is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn poll(self, connected: Arc<AtomicBool>, events: UnboundedSender<Event>) {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(POLL_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = events.closed() => break,
+            }
+            if connected.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            for peer in self.sessions() {
+                match self.receive(&peer).await.map_err(|e| e.to_string()) {
+                    Ok(messages) => {
+                        for message in messages {
+                            let _ = events.send(Event::Message { peer: peer.clone(), message });
+                        }
+                    },
+                    Err(e) => { warn!("Error refreshing messages from {}: {}", peer, e); }
+                }
+            }
+        }
+    }
+}
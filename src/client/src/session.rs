@@ -1,3 +1,4 @@
+use std::collections::BTreeSet;
 use std::sync::{Arc, Mutex};
 use std::error::Error;
 use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
@@ -9,13 +10,24 @@ use hkdf::Hkdf;
 use sha2::Sha256;
 use crate::file::SessionKey;
 use crate::message::Message;
-use crate::socket::{RequestPayload};
 use crate::support::{dh_ratchet_update, hkdf_ratchet_update, verify_spk_signature, X25519};
-use crate::util::MAX_TIME_UPDATE;
+use crate::util::{MAX_SEEN_AHEAD, MAX_TIME_UPDATE};
 
-#[derive(Debug)]
+/// What the responder needs to derive a session the initiator started: the ephemeral key and
+/// the id of the claimed one-time prekey, sent in one request with the first message.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub ekp: [u8; 32],
+    pub opk_id: i32,
+    /// The first message, encrypted with the first key of the sending chain once it is written.
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Session {
     pub target: String,
+    /// The ephemeral key of the initiator, the same on both sides of the session.
+    pub id: [u8; 32],
     pub root_key: [u8; 32],
     pub send_key: [u8; 32],
     pub recv_key: [u8; 32],
@@ -26,10 +38,45 @@ pub struct Session {
     pub reverse: bool,
     pub check: bool,
     pub record: Vec<[u8; 32]>,
+    /// Kept by the initiator until the request carrying it has reached the server.
+    pub handshake: Option<Handshake>,
+    /// When the server received the last message read under this session, 0 before the first one.
+    pub received: i64,
+    /// Every message from the peer the server numbered up to `seq` has been read.
+    pub seq: i64,
+    /// Numbers above `seq` of messages read out of order, as after a lease ran out.
+    pub ahead: BTreeSet<i64>,
+    /// Set for a session kept besides the active one with the same peer, see [`Session::yields_to`].
+    pub alternate: bool,
+    /// Messages written or read under this session. It only grows, so of two copies of the
+    /// session the one with more steps is the newer.
+    pub steps: u64,
+}
+
+/// What a session file holds, read back into a [`Session`] by [`Session::load`].
+pub struct SessionState {
+    pub id: [u8; 32],
+    pub root_key: [u8; 32],
+    pub recv_key: [u8; 32],
+    pub send_key: [u8; 32],
+    pub ratchet_private: [u8; 32],
+    pub ratchet_public: [u8; 32],
+    pub last_pub: [u8; 32],
+    pub time: i64,
+    pub reverse: bool,
+    pub record: Vec<[u8; 32]>,
+    pub check: bool,
+    pub handshake: Option<Handshake>,
+    pub received: i64,
+    pub seq: i64,
+    pub ahead: BTreeSet<i64>,
+    pub steps: u64,
 }
 
 impl Session {
-    pub async fn new(
+    /// Starts a session from the prekey bundle of `target`. Nothing is sent yet, the request
+    /// goes out together with the first message.
+    pub fn new(
         target: &str,
         ikp: [u8; 32],
         spk: [u8; 32],
@@ -40,10 +87,10 @@ impl Session {
     ) -> Result<Self, Box<dyn Error>> {
         verify_spk_signature(&ikp, &spk, &spk_sig)?;
 
-        let (name, ik_private, ik_public) = {
+        let ik_private = {
             let account_temp = account.lock().unwrap();
             let account_ref = account_temp.as_ref().unwrap();
-            (account_ref.name().to_string(), account_ref.ik().private_key, account_ref.ik().public_key)
+            account_ref.ik().private_key
         };
         
         let ek = X25519::rand_key();
@@ -71,12 +118,11 @@ impl Session {
             Ok::<[u8; 32], Box<dyn Error>>(root_key)
         }?;
 
-        RequestPayload::send(name.to_string(), ik_public, ek.public, id, target.to_string()).await?;
-        
         let shared = x25519(ek.private, opk);
         let (recv_key, send_key) = dh_ratchet_update(&shared, &mut root_key, false)?;
 
         Ok(Self { 
+            id: ek.public,
             root_key,
             ratchet_private: ek.private,
             ratchet_public: ek.public,
@@ -88,6 +134,12 @@ impl Session {
             reverse: false,
             check: true,
             record: Vec::new(),
+            handshake: Some(Handshake { ekp: ek.public, opk_id: id, message: None }),
+            received: 0,
+            seq: 0,
+            ahead: BTreeSet::new(),
+            alternate: false,
+            steps: 0,
         })
     }
     
@@ -140,6 +192,7 @@ impl Session {
         
 
         Ok(Self { 
+            id: ekp,
             root_key,
             ratchet_private: opk_private_key,
             ratchet_public: opk_private_key,
@@ -151,53 +204,99 @@ impl Session {
             target: target.to_string(),
             check: true,
             record: Vec::new(),
+            handshake: None,
+            received: 0,
+            seq: 0,
+            ahead: BTreeSet::new(),
+            alternate: false,
+            steps: 0,
         })
     }
     
-    pub fn load(
-        target: &str, 
-        root_key: [u8; 32], 
-        recv_key: [u8; 32], 
-        send_key: [u8; 32], 
-        ratchet_private: [u8; 32],
-        ratchet_public: [u8; 32],
-        last_pub: [u8; 32],
-        time: i64, 
-        reverse: bool,
-        record: Vec<[u8; 32]>,
-        check: bool,
-    ) -> Self {
+    pub fn load(target: &str, state: SessionState) -> Self {
         Self { 
-            root_key,
-            recv_key,
-            send_key,
-            ratchet_public,
-            ratchet_private,
-            last_pub,
-            time,
-            reverse,
-            record,
-            check,
+            id: state.id,
+            root_key: state.root_key,
+            recv_key: state.recv_key,
+            send_key: state.send_key,
+            ratchet_public: state.ratchet_public,
+            ratchet_private: state.ratchet_private,
+            last_pub: state.last_pub,
+            time: state.time,
+            reverse: state.reverse,
+            record: state.record,
+            check: state.check,
+            handshake: state.handshake,
+            received: state.received,
+            seq: state.seq,
+            ahead: state.ahead,
+            alternate: false,
+            steps: state.steps,
             target: target.to_string(),
         }
     }
 
     pub fn name(&self) -> &str { &self.target }
+    
+    /// Whether a session the peer requested at `timestamp` takes the place of this one. If both
+    /// sides started a session, each keeps the one started by the smaller identity key, so they
+    /// end up on the same one. A request made after the peer already wrote under this session
+    /// means the peer started over.
+    pub fn yields_to(&self, timestamp: i64, own_ik: &[u8; 32], peer_ik: &[u8; 32]) -> bool {
+        let crossed = !self.reverse && (self.received == 0 || self.received >= timestamp);
+        !crossed || own_ik > peer_ik
+    }
+    
+    /// Whether this account started the session and has not heard from the peer under it yet.
+    pub fn awaiting_reply(&self) -> bool {
+        !self.reverse && self.received == 0
+    }
+
+    /// Whether the message the server numbered `seq` has been read already. Messages from before
+    /// the server numbered them have `seq` 0 and are never taken for one read before.
+    pub fn seen(&self, seq: i64) -> bool {
+        seq > 0 && (seq <= self.seq || self.ahead.contains(&seq))
+    }
+
+    /// Records the message numbered `seq` as read. A number that never arrives, like that of a
+    /// message the server dropped, would hold the rest back, so past [`MAX_SEEN_AHEAD`] the oldest
+    /// ones move into `seq` and what is missing below them is given up on.
+    pub fn mark_seen(&mut self, seq: i64) {
+        if seq <= self.seq { return; }
 
-    pub fn revive_message(&mut self, payload: String, timestamp: i64, account: &str) -> Result<Message, Box<dyn Error>> {
+        self.ahead.insert(seq);
+        while let Some(&first) = self.ahead.first() {
+            if first != self.seq + 1 && self.ahead.len() <= MAX_SEEN_AHEAD { break; }
+            self.ahead.remove(&first);
+            self.seq = first;
+        }
+    }
+
+    /// Decrypts the message numbered `seq` the server received from the peer at `received`. The
+    /// ratchet only moves on if it succeeds, so a message meant for another session with the
+    /// same peer leaves this one as it was. The number is saved together with the ratchet, so a
+    /// message read once is never read again.
+    pub fn revive_message(&mut self, payload: String, received: i64, seq: i64, account: &str) -> Result<Message, Box<dyn Error>> {
+        let mut next = self.clone();
         let message = match payload.chars().next() {
-            Some('0') => self.recv(hex::decode(&payload[1..])?),
-            Some('1') => self.recv_update_initiative(hex::decode(&payload[1..])?),
-            Some('2') => self.recv_update_passive(hex::decode(&payload[1..])?),
+            Some('0') => next.recv(hex::decode(&payload[1..])?),
+            Some('1') => next.recv_update_initiative(hex::decode(&payload[1..])?),
+            Some('2') => next.recv_update_passive(hex::decode(&payload[1..])?),
             _ => Err("Invalid message type".into())
         }?;
         
+        // A reply means the responder has the session, so the request is no longer needed.
+        next.handshake = None;
+        next.received = next.received.max(received);
+        next.mark_seen(seq);
+        next.steps += 1;
+        *self = next;
         SessionKey::overload(&self, account)?;
-        Ok(Message { sender: false, timestamp, text: message })
+        Ok(Message::open(message, received))
     }
 
     pub fn add_message(&mut self, message: Message, account: &str) -> Result<String, Box<dyn Error>> {
-        let payload = if self.check == false {
+        let payload = if !self.check {
             self.send_update_passive(&message)
         } else if self.time >= MAX_TIME_UPDATE {
             self.send_update_initiative(&message)
@@ -205,10 +304,25 @@ impl Session {
             self.send(&message)
         }?;
         
+        if let Some(handshake) = self.handshake.as_mut() {
+            handshake.message.get_or_insert_with(|| payload.clone());
+        }
+        self.steps += 1;
         SessionKey::overload(&self, account)?;
         Ok(payload)
     }
     
+    /// The handshake to send before anything else, once the first message is written.
+    pub fn pending_request(&self) -> Option<&Handshake> {
+        self.handshake.as_ref().filter(|handshake| handshake.message.is_some())
+    }
+    
+    /// Forgets the handshake after the request carrying it has reached the server.
+    pub fn confirm_request(&mut self, account: &str) -> Result<(), Box<dyn Error>> {
+        self.handshake = None;
+        SessionKey::overload(&self, account)
+    }
+    
     fn send(&mut self, message: &Message) -> Result<String, Box<dyn Error>> {
         let message_key = hkdf_ratchet_update(&mut self.send_key)?;
         
@@ -218,7 +332,7 @@ impl Session {
         OsRng.fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
         
-        let ciphertext = cipher.encrypt(nonce, message.text.as_bytes())
+        let ciphertext = cipher.encrypt(nonce, message.seal().as_slice())
             .map_err(|e| format!("Failed to encrypt message: {}", e))?;
         
         self.time += 1;
@@ -235,7 +349,7 @@ impl Session {
         OsRng.fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
         
-        let ciphertext = cipher.encrypt(nonce, message.text.as_bytes())
+        let ciphertext = cipher.encrypt(nonce, message.seal().as_slice())
             .map_err(|e| format!("Failed to encrypt message: {}", e))?;
         
         self.time += 1;
@@ -266,7 +380,7 @@ impl Session {
         OsRng.fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
         
-        let ciphertext = cipher.encrypt(nonce, message.text.as_bytes())
+        let ciphertext = cipher.encrypt(nonce, message.seal().as_slice())
             .map_err(|e| format!("Failed to encrypt message: {}", e))?;
         
         Ok(format!(
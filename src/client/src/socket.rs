@@ -1,32 +1,261 @@
-use reqwest::Client;
+use reqwest::{Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::error::Error;
+use std::fmt;
 use std::sync::{Arc, Mutex};
-use log::{info};
+use std::time::{Duration, Instant};
+use log::{info, warn};
 use crate::account::Account;
-use crate::file::SessionKey;
-use crate::key::{AccountKeys, OneTimePreKey};
-use crate::session::Session;
-use crate::support::string_to_v32;
+use crate::file::{valid_name, AccountLock, LocalLog, MessageHistory, SessionKey};
+use crate::key::{AccountKeys, IdentityKeyPair, OneTimePreKey};
+use crate::message::Message;
+use crate::session::{Handshake, Session};
+use crate::support::{string_to_v32, xeddsa_sign};
+use crate::transparency::{KeyProof, TreeHead};
+use crate::util::{LOGIN_CONTEXT, UPDATE_CONTEXT};
+
+/// The stable error codes of the server API.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidAccount,
+    InvalidKey,
+    InvalidTimestamp,
+    InvalidRequest,
+    Unauthorized,
+    KeyProofRequired,
+    UserNotFound,
+    NoPrekeys,
+    RequestNotFound,
+    RequestExists,
+    MessageTooLarge,
+    QueueFull,
+    RateLimited,
+    Internal,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+}
+
+/// A request the server answered with an error. Callers can downcast the boxed errors of this
+/// module to it and match on `code`.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        
+        match serde_json::from_str::<ErrorBody>(&text) {
+            Ok(body) => Self { status, code: body.code, message: body.message },
+            Err(_) => Self { status, code: ErrorCode::Unknown, message: text },
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?}): {}", self.status, self.code, self.message)
+    }
+}
+
+impl Error for ApiError {}
+
+struct Credential {
+    server: String,
+    account: String,
+    ik_private: [u8; 32],
+    token: Mutex<Option<(String, Instant)>>,
+    _lock: AccountLock,
+}
+
+/// The server an account talks to and the identity its requests are authenticated as. Clones
+/// share the cached token, so several accounts can be signed in within one process. The account
+/// stays locked to this process until the last clone is dropped, see [`AccountLock`].
+#[derive(Clone)]
+pub struct Connection {
+    credential: Arc<Credential>,
+}
+
+/// Signs `account` in to the server at `SERVER_URL`.
+pub fn sign_in(account: &Account) -> Result<Connection, Box<dyn Error>> {
+    Connection::new(&std::env::var("SERVER_URL")?, account)
+}
 
 #[derive(Serialize, Deserialize, Debug)]
-struct SearchPayload {
+struct ChallengePayload { account: String }
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChallengeResponse { challenge: String }
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LoginPayload {
     account: String,
-    target: String,
+    challenge: String,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LoginResponse {
+    token: String,
+    expires_in: u64,
 }
 
-pub async fn search(account: &str, target: &str) -> Result<Vec<String>, Box<dyn Error>> {
+/// Asks the server for a challenge that `account` signs once, within a minute, to log in or to
+/// authorize new keys.
+async fn get_challenge(server: &str, account: &str) -> Result<String, Box<dyn Error>> {
     let response = Client::new()
-        .post(std::env::var("SERVER_URL")? + "/search/")
-        .json(&SearchPayload { account: account.to_string(), target: target.to_string() })
+        .post(server.to_string() + "/auth/challenge/")
+        .json(&ChallengePayload { account: account.to_string() })
         .send()
         .await?;
+    
+    if !response.status().is_success() {
+        return Err(ApiError::from_response(response).await.into());
+    }
+    Ok(response.json::<ChallengeResponse>().await?.challenge)
+}
+
+impl Connection {
+    pub fn new(server: &str, account: &Account) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            credential: Arc::new(Credential {
+                server: server.trim_end_matches('/').to_string(),
+                account: account.name().to_string(),
+                ik_private: account.ik().private_key,
+                token: Mutex::new(None),
+                _lock: AccountLock::acquire(account.name())?,
+            })
+        })
+    }
+    
+    pub fn server(&self) -> &str {
+        &self.credential.server
+    }
+    
+    pub fn account(&self) -> &str {
+        &self.credential.account
+    }
+    
+    /// Returns the cached bearer token, logging in again with a signed challenge when it is
+    /// missing, about to expire or `refresh` is set.
+    pub async fn token(&self, refresh: bool) -> Result<String, Box<dyn Error>> {
+        let credential = &self.credential;
+        if let (false, Some((token, expiry))) = (refresh, &*credential.token.lock().unwrap()) {
+            if *expiry > Instant::now() {
+                return Ok(token.clone());
+            }
+        }
+        
+        let challenge = get_challenge(&credential.server, &credential.account).await?;
+        let message = format!("{}:{}:{}", LOGIN_CONTEXT, credential.account, challenge);
+        let response = Client::new()
+            .post(credential.server.clone() + "/auth/login/")
+            .json(&LoginPayload {
+                account: credential.account.clone(),
+                challenge,
+                signature: hex::encode(xeddsa_sign(&credential.ik_private, message.as_bytes())),
+            })
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            return Err(ApiError::from_response(response).await.into());
+        }
+        let login = response.json::<LoginResponse>().await?;
+        
+        // Refresh a little early so a token never expires between being read and being used.
+        let expiry = Instant::now() + Duration::from_secs(login.expires_in.saturating_sub(30));
+        *credential.token.lock().unwrap() = Some((login.token.clone(), expiry));
+        
+        info!("Logged in as {}", credential.account);
+        Ok(login.token)
+    }
+    
+    /// Posts `body` to `path` with the bearer token attached, retrying once with a fresh token if
+    /// the server no longer accepts the cached one.
+    async fn post<T: Serialize + ?Sized>(&self, path: &str, body: &T) -> Result<Response, Box<dyn Error>> {
+        let url = self.credential.server.clone() + path;
+        let bearer = self.token(false).await?;
+        
+        let response = Client::new()
+            .post(&url)
+            .bearer_auth(bearer)
+            .json(body)
+            .send()
+            .await?;
+        
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let bearer = self.token(true).await?;
+            return Ok(Client::new()
+                .post(&url)
+                .bearer_auth(bearer)
+                .json(body)
+                .send()
+                .await?);
+        }
+        
+        Ok(response)
+    }
+}
+
+/// One page of a paginated endpoint. `next` is the cursor of the following page, if any.
+#[derive(Deserialize, Debug)]
+struct Page<T, C> {
+    items: Vec<T>,
+    next: Option<C>,
+}
+
+#[derive(Serialize, Debug)]
+struct PagePayload<C> {
+    after: Option<C>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SearchPayload {
+    target: String,
+    prefix: bool,
+}
+
+/// Returns the account named `target`, or with `prefix` the first page of discoverable accounts
+/// whose names start with it. Names that could not be stored as a peer are left out.
+pub async fn search(connection: &Connection, target: &str, prefix: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let response = connection.post("/search/", &SearchPayload { target: target.to_string(), prefix }).await?;
+
+    if response.status().is_success() {
+        let result = response.json::<Page<String, String>>().await?;
+        Ok(result.items.into_iter().filter(|name| valid_name(name)).collect())
+    } else {
+        Err(ApiError::from_response(response).await.into())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DiscoverablePayload {
+    discoverable: Option<bool>,
+}
+
+/// Changes whether others can find the account by prefix search, if `discoverable` is given,
+/// and returns the current setting.
+pub async fn set_discoverable(connection: &Connection, discoverable: Option<bool>) -> Result<bool, Box<dyn Error>> {
+    let response = connection.post("/discoverable/", &DiscoverablePayload { discoverable }).await?;
 
     if response.status().is_success() {
-        let result = response.json::<Vec<String>>().await?;
-        Ok(result)
+        let result = response.json::<DiscoverablePayload>().await?;
+        Ok(result.discoverable.unwrap_or(false))
     } else {
-        Err(format!("Failed with status: {}", response.status()).into())
+        Err(ApiError::from_response(response).await.into())
     }
 }
 
@@ -41,49 +270,188 @@ pub struct SessionResponse {
     spk_signature: String,
     opk: String, 
     id: i32,
+    log: KeyProof,
 }
 
-pub async fn get_session(target: &str, account: Arc<Mutex<Option<Account>>>) -> Result<Session, Box<dyn Error>> {
-    let response = Client::new()
-        .post(std::env::var("SERVER_URL")? + "/session/")
-        .json(&SessionPayload { target: target.to_string() })
-        .send()
-        .await?;
+#[derive(Serialize, Debug)]
+struct ConsistencyPayload {
+    from: u64,
+    to: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConsistencyResponse {
+    proof: Vec<String>,
+}
+
+async fn get_tree_head(connection: &Connection) -> Result<TreeHead, Box<dyn Error>> {
+    let response = connection.post("/log/head/", &()).await?;
+
+    if response.status().is_success() {
+        Ok(response.json::<TreeHead>().await?)
+    } else {
+        Err(ApiError::from_response(response).await.into())
+    }
+}
+
+async fn get_consistency(connection: &Connection, from: u64, to: u64) -> Result<Vec<String>, Box<dyn Error>> {
+    let response = connection.post("/log/consistency/", &ConsistencyPayload { from, to }).await?;
+
+    if response.status().is_success() {
+        Ok(response.json::<ConsistencyResponse>().await?.proof)
+    } else {
+        Err(ApiError::from_response(response).await.into())
+    }
+}
+
+/// Checks a tree head against the one the account saw last and remembers it if it is newer.
+async fn check_tree_head(connection: &Connection, head: &TreeHead) -> Result<(), Box<dyn Error>> {
+    let account = connection.account();
+    let mut state = LocalLog::load(account)?;
+    state.verify_head(head)?;
+    
+    let known = state.head().map(|known| known.size);
+    let path = match known {
+        Some(size) if size != head.size => get_consistency(connection, size.min(head.size), size.max(head.size)).await?,
+        _ => vec![],
+    };
+    state.verify_consistency(head, &path)?;
+    
+    LocalLog::save(account, &state)
+}
+
+/// Fetches the current tree head and checks it against the history the account has seen, so a
+/// server that shows this account a different log than others is noticed.
+pub async fn refresh_tree_head(connection: &Connection) -> Result<(), Box<dyn Error>> {
+    let head = get_tree_head(connection).await?;
+    check_tree_head(connection, &head).await
+}
+
+/// Verifies that the identity key in a prekey bundle is the one the log publishes for `target`,
+/// so the server cannot show this account a key other users do not see.
+async fn verify_bundle(connection: &Connection, target: &str, ik_public: &str, proof: &KeyProof) -> Result<(), Box<dyn Error>> {
+    check_tree_head(connection, &proof.head).await?;
+    
+    let mut state = LocalLog::load(connection.account())?;
+    state.verify_key(target, ik_public, proof)?;
+    LocalLog::save(connection.account(), &state)?;
+    
+    info!("Verified version {} of the identity key of {} in the key transparency log", proof.version, target);
+    Ok(())
+}
+
+pub async fn get_session(connection: &Connection, target: &str, account: Arc<Mutex<Option<Account>>>) -> Result<Session, Box<dyn Error>> {
+    let response = connection.post("/session/", &SessionPayload { target: target.to_string() }).await?;
 
     if response.status().is_success() {
         let result = response.json::<SessionResponse>().await?;
+        // A logged bundle of another account would pass the checks below under its own name.
+        if result.account != target {
+            return Err(format!("The server returned the keys of {} for {}", result.account, target).into());
+        }
+        verify_bundle(connection, &result.account, &result.ik_public, &result.log).await?;
         
         let session = Session::new(
             &*result.account,
             string_to_v32(&result.ik_public).unwrap(),
             string_to_v32(&result.spk_public).unwrap(),
-            vec![],
+            hex::decode(&result.spk_signature)?,
             string_to_v32(&result.opk).unwrap(),
             result.id,
             account.clone()
-        ).await?;
+        )?;
         
         SessionKey::save(&session, account.lock().unwrap().as_ref().unwrap().name())?;
         info!("Loaded session for {}", target);
         Ok(session)
     } else {
-        Err(format!("Failed with status: {}", response.status()).into())
+        Err(ApiError::from_response(response).await.into())
     }
 }
 
-pub async fn get_session_list(account: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    let response = Client::new()
-        .post(std::env::var("SERVER_URL")? + "/list/session/")
-        .json(&SessionPayload { target: account.to_string() })
-        .send()
-        .await?;
+/// A pending session request: the account on the other side and when the request was made.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingRequest {
+    pub account: String,
+    pub timestamp: i64,
+}
+
+/// Every request on `path`, leaving out accounts whose names could not be stored as a peer.
+async fn get_request_list(connection: &Connection, path: &str) -> Result<Vec<PendingRequest>, Box<dyn Error>> {
+    let mut requests = vec![];
+    let mut after = None;
+    
+    loop {
+        let response = connection.post(path, &PagePayload { after }).await?;
+        if !response.status().is_success() {
+            return Err(ApiError::from_response(response).await.into());
+        }
+        
+        let page = response.json::<Page<PendingRequest, String>>().await?;
+        requests.extend(page.items.into_iter().filter(|request| valid_name(&request.account)));
+        match page.next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+    
+    Ok(requests)
+}
+
+/// The session requests waiting for the signed in account to accept them.
+pub async fn get_session_list(connection: &Connection) -> Result<Vec<PendingRequest>, Box<dyn Error>> {
+    let session = get_request_list(connection, "/list/session/").await?;
+    info!("Find {} sessions", session.len());
+    Ok(session)
+}
+
+/// The session requests the signed in account made that are still pending.
+pub async fn get_outgoing_list(connection: &Connection) -> Result<Vec<PendingRequest>, Box<dyn Error>> {
+    get_request_list(connection, "/list/session/outgoing/").await
+}
+
+/// Every account the signed in account has blocked.
+pub async fn get_block_list(connection: &Connection) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut blocked = vec![];
+    let mut after = None;
+    
+    loop {
+        let response = connection.post("/list/block/", &PagePayload { after }).await?;
+        if !response.status().is_success() {
+            return Err(ApiError::from_response(response).await.into());
+        }
+        
+        let page = response.json::<Page<String, String>>().await?;
+        blocked.extend(page.items);
+        match page.next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+    
+    Ok(blocked)
+}
+
+/// Blocks `target`, which also drops its pending request and queued messages on the server.
+pub async fn block_user(connection: &Connection, target: &str) -> Result<(), Box<dyn Error>> {
+    let response = connection.post("/block/", &SessionPayload { target: target.to_string() }).await?;
 
     if response.status().is_success() {
-        let session = response.json::<Vec<String>>().await?;
-        info!("Find {} sessions", session.len());
-        Ok(session)
+        info!("Blocked {}", target);
+        Ok(())
+    } else {
+        Err(ApiError::from_response(response).await.into())
+    }
+}
+
+pub async fn unblock_user(connection: &Connection, target: &str) -> Result<(), Box<dyn Error>> {
+    let response = connection.post("/unblock/", &SessionPayload { target: target.to_string() }).await?;
+
+    if response.status().is_success() {
+        info!("Unblocked {}", target);
+        Ok(())
     } else {
-        Err(format!("Failed with status: {}", response.status()).into())
+        Err(ApiError::from_response(response).await.into())
     }
 }
 
@@ -93,6 +461,13 @@ pub struct OPKPayload {
     id: i32,
 }
 
+/// How an upload proves it may set the keys of `account`. Only a new account needs no proof.
+pub enum KeyUpdate<'a> {
+    Register,
+    Signed(&'a IdentityKeyPair),
+    Recovery(&'a str),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadPayload {
     account: String,
@@ -100,20 +475,66 @@ pub struct UploadPayload {
     spk_public: String,
     spk_signature: String,
     opk: Vec<OPKPayload>,
+    recovery: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proof: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_code: Option<String>,
 }
 
 impl UploadPayload {
-    pub async fn new(account: &AccountKeys, name: &str, opk: Vec<OneTimePreKey>) -> Result<(), Box<dyn Error>> {
-        let key = UploadPayload {
+    /// What the previous identity key signs to authorize these keys: all of them and a fresh
+    /// challenge, so the server cannot reuse the proof for other prekeys or later.
+    fn update_message(&self, challenge: &str) -> String {
+        let opks = self.opk.iter().fold(Sha256::new(), |digest, key| digest.chain_update(format!("{}:{};", key.id, key.key)));
+        format!("{}:{}:{}:{}:{}:{}:{}:{}",
+            UPDATE_CONTEXT,
+            self.account,
+            challenge,
+            self.ik_public,
+            self.spk_public,
+            self.spk_signature,
+            self.recovery.as_deref().unwrap_or_default(),
+            hex::encode(opks.finalize())
+        )
+    }
+    
+    pub async fn new(
+        server: &str,
+        account: &AccountKeys, 
+        name: &str, 
+        opk: Vec<OneTimePreKey>, 
+        recovery: &str, 
+        update: KeyUpdate<'_>
+    ) -> Result<(), Box<dyn Error>> {
+        let mut key = UploadPayload {
             account: name.to_string(),
             ik_public: hex::encode(&account.identity_keypair.public_key),
             spk_public: hex::encode(&account.signed_prekey.public_key),
             spk_signature: hex::encode(&account.signed_prekey.signature),
             opk: opk.iter().map(|k| OPKPayload { key: hex::encode(&k.key), id: k.id, }).collect(),
+            recovery: Some(hex::encode(Sha256::digest(recovery.as_bytes()))),
+            proof: None,
+            challenge: None,
+            recovery_code: None,
         };
         
+        let server = server.trim_end_matches('/');
+        match update {
+            KeyUpdate::Register => {},
+            KeyUpdate::Signed(previous) => {
+                let challenge = get_challenge(server, name).await?;
+                let message = key.update_message(&challenge);
+                key.proof = Some(hex::encode(xeddsa_sign(&previous.private_key, message.as_bytes())));
+                key.challenge = Some(challenge);
+            },
+            KeyUpdate::Recovery(code) => key.recovery_code = Some(code.to_string()),
+        }
+        
         let response = Client::new()
-            .post(std::env::var("SERVER_URL")? + "/create/")
+            .post(server.to_string() + "/create/")
             .json(&key)
             .send()
             .await?;
@@ -122,7 +543,7 @@ impl UploadPayload {
             info!("Uploaded keys");
             Ok(())
         } else {
-            Err(Box::from(format!("Failed to upload: {}", response.status())))
+            Err(ApiError::from_response(response).await.into())
         }
     }
 }
@@ -134,48 +555,63 @@ pub struct RequestPayload {
     ikp: String,
     ekp: String,
     opk_id: i32,
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct DeclinePayload {
+    target: String,
+    notify: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct NewRequestPayload {
+    target: String,
+    ikp: String,
+    ekp: String,
+    opk_id: i32,
+    message: Option<String>,
 }
 
 impl RequestPayload {
-    pub async fn send(account: String, ikp: [u8; 32], ekp: [u8; 32], opk_id: i32, target: String) -> Result<(), Box<dyn Error>> {
-        let response = Client::new()
-            .post(std::env::var("SERVER_URL")? + "/create/session/")
-            .json(&Self {
-                account,
-                target,
-                ikp: hex::encode(ikp),
-                ekp: hex::encode(ekp),
-                opk_id,
-            })
-            .send()
-            .await?;
+    /// Sends the handshake of a session with `target` together with its first message. A request
+    /// the server already holds counts as sent, so a retry after a lost response is harmless.
+    pub async fn send(connection: &Connection, ikp: [u8; 32], handshake: &Handshake, target: &str) -> Result<(), Box<dyn Error>> {
+        let response = connection.post("/create/session/", &NewRequestPayload {
+            target: target.to_string(),
+            ikp: hex::encode(ikp),
+            ekp: hex::encode(handshake.ekp),
+            opk_id: handshake.opk_id,
+            message: handshake.message.clone(),
+        }).await?;
 
         if response.status().is_success() {
             info!("Sent request");
+            return Ok(());
+        }
+        
+        let error = ApiError::from_response(response).await;
+        if error.code == ErrorCode::RequestExists {
+            info!("Request to {} is already pending", target);
             Ok(())
         } else {
-            Err(Box::from(format!("Failed to send request: {}", response.status())))
+            Err(error.into())
         }
     }
     
-    pub async fn receive(target: String, account: Arc<Mutex<Option<Account>>>) -> Result<Session, Box<dyn Error>> {
-        let name = {
-            let account_temp = account.lock().unwrap();
-            let account_ref = account_temp.as_ref().unwrap();
-            account_ref.name().to_string()
-        };
-        
-        let response = Client::new()
-            .post(std::env::var("SERVER_URL")? + "/get/session/")
-            .json(&SearchPayload { target: name , account: target.clone() })
-            .send()
-            .await?;
+    /// Accepts the request from `target` and returns the session with the first message, if it
+    /// came with one and could be read.
+    pub async fn receive(connection: &Connection, target: String, account: Arc<Mutex<Option<Account>>>) -> Result<(Session, Option<Message>), Box<dyn Error>> {
+        let response = connection.post("/get/session/", &SessionPayload { target: target.clone() }).await?;
 
         if response.status().is_success() {
             info!("Received request");
             let result = response.json::<Self>().await?;
             
-            let session = Session::from(
+            let mut session = Session::from(
                 account.clone(),
                 string_to_v32(&result.ikp).unwrap(),
                 string_to_v32(&result.ekp).unwrap(),
@@ -183,58 +619,179 @@ impl RequestPayload {
                 &target
             )?;
             
-            SessionKey::save(&session, account.lock().unwrap().as_ref().unwrap().name())?;
+            let owner = account.lock().unwrap().clone().ok_or("Not signed in")?;
+            
+            // If this account has a session with the peer already, only one of the two stays
+            // active. The other one is kept to read what the peer sent under it.
+            let keep = match SessionKey::load(&target, account.clone()) {
+                Ok(mut existing) => {
+                    if existing.yields_to(result.timestamp, &owner.ik().public_key, &string_to_v32(&result.ikp)?) {
+                        session.seq = existing.seq;
+                        session.ahead = existing.ahead.clone();
+                        existing.alternate = true;
+                        SessionKey::save(&existing, owner.name())?;
+                        false
+                    } else {
+                        info!("Keeping the session this account started with {}", target);
+                        true
+                    }
+                },
+                Err(_) => false,
+            };
+            session.alternate = keep;
+            SessionKey::save(&session, owner.name())?;
             info!("Loaded session for {}", target);
             
-            Ok(session)
+            // The first message only advances the receiving chain, so a failure here does not
+            // keep the messages after it from decrypting.
+            let first = match result.message.map(|message| session.revive_message(message, result.timestamp, 0, owner.name())) {
+                Some(Ok(message)) => match MessageHistory::append(&owner, &target, message.clone()) {
+                    Ok(stored) => Some(stored),
+                    Err(e) => {
+                        warn!("Error saving the first message from {}: {:?}", target, e);
+                        Some(message)
+                    }
+                },
+                Some(Err(e)) => {
+                    warn!("Error reading the first message from {}: {:?}", target, e);
+                    None
+                },
+                None => None,
+            };
+            
+            ack(connection, &[], std::slice::from_ref(&target)).await?;
+            if keep {
+                Ok((SessionKey::load(&target, account)?, first))
+            } else {
+                Ok((session, first))
+            }
+        } else {
+            Err(ApiError::from_response(response).await.into())
+        }
+    }
+    
+    /// Withdraws the request the signed in account made to `target`.
+    pub async fn cancel(connection: &Connection, target: &str) -> Result<(), Box<dyn Error>> {
+        let response = connection.post("/cancel/session/", &SessionPayload { target: target.to_string() }).await?;
+
+        if response.status().is_success() {
+            info!("Cancelled request to {}", target);
+            Ok(())
+        } else {
+            Err(ApiError::from_response(response).await.into())
+        }
+    }
+    
+    /// Deletes the request from `target` without accepting it, telling `target` if `notify` is set.
+    pub async fn decline(connection: &Connection, target: &str, notify: bool) -> Result<(), Box<dyn Error>> {
+        let response = connection.post("/decline/session/", &DeclinePayload { target: target.to_string(), notify }).await?;
+
+        if response.status().is_success() {
+            info!("Declined request from {}", target);
+            Ok(())
         } else {
-            Err(Box::from(format!("Failed to receive request: {}", response.status())))
+            Err(ApiError::from_response(response).await.into())
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessagePayload {
+    pub id: i32,
     account: String,
     target: String,
     pub message: String,
     pub timestamp: i64,
+    /// When the server received the message.
+    #[serde(default)]
+    pub received: i64,
+    /// Position of the message among those the sender wrote to this account, counted by the server.
+    #[serde(default)]
+    pub seq: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct MessageQueryPayload {
+    target: String,
+    after: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct NewMessagePayload {
+    target: String,
+    message: String,
+    timestamp: i64,
 }
 
 impl MessagePayload {
-    pub async fn send(account: &str, target: &str, message: String, timestamp: i64) -> Result<(), Box<dyn Error>> {
-        let response = Client::new()
-            .post(std::env::var("SERVER_URL")? + "/create/message/")
-            .json(&Self { 
-                account: account.to_string(), 
-                target: target.to_string(), 
-                message, 
-                timestamp 
-            })
-            .send()
-            .await?;
+    pub fn sender(&self) -> &str {
+        &self.account
+    }
+    
+    pub async fn send(connection: &Connection, target: &str, message: String, timestamp: i64) -> Result<(), Box<dyn Error>> {
+        let response = connection.post("/create/message/", &NewMessagePayload { 
+            target: target.to_string(), 
+            message, 
+            timestamp 
+        }).await?;
 
         if response.status().is_success() {
             info!("Sent message");
             Ok(())
         } else {
-            Err(Box::from(format!("Failed to send message: {}", response.status())))
+            Err(ApiError::from_response(response).await.into())
         }
     }
     
-    pub async fn receive(account: String, target: String) -> Result<Vec<MessagePayload>, Box<dyn Error>> {
-        let response = Client::new()
-            .post(std::env::var("SERVER_URL")? + "/message/")
-            .json(&SearchPayload { account, target })
-            .send()
-            .await?;
+    /// Fetches the page of messages from `target` that follows the cursor `after`, together with
+    /// the cursor of the next page while more are queued.
+    pub async fn receive(connection: &Connection, target: String, after: Option<i32>) -> Result<(Vec<MessagePayload>, Option<i32>), Box<dyn Error>> {
+        let response = connection.post("/message/", &MessageQueryPayload { target, after }).await?;
 
         if response.status().is_success() {
-            info!("Received message");
-            let result = response.json::<Vec<MessagePayload>>().await?;
-            Ok(result)
+            let result = response.json::<Page<MessagePayload, i32>>().await?;
+            info!("Received {} messages", result.items.len());
+            Ok((result.items, result.next))
         } else {
-            Err(Box::from(format!("Failed to receive message: {}", response.status())))
+            Err(ApiError::from_response(response).await.into())
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Debug)]
+struct AckPayload<'a> {
+    messages: &'a [i32],
+    requests: &'a [String],
+}
+
+/// Tells the server that these messages and session requests are stored locally, so it stops
+/// delivering them. Anything not acknowledged is delivered again later.
+pub async fn ack(connection: &Connection, messages: &[i32], requests: &[String]) -> Result<(), Box<dyn Error>> {
+    let response = connection.post("/ack/", &AckPayload { messages, requests }).await?;
+
+    if response.status().is_success() {
+        info!("Acknowledged {} messages and {} requests", messages.len(), requests.len());
+        Ok(())
+    } else {
+        Err(ApiError::from_response(response).await.into())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyChangePayload {
+    pub target: String,
+    pub ik_public: String,
+    pub timestamp: i64,
+}
+
+pub async fn get_key_changes(connection: &Connection) -> Result<Vec<KeyChangePayload>, Box<dyn Error>> {
+    let response = connection.post("/key/change/", &()).await?;
+
+    if response.status().is_success() {
+        let changes = response.json::<Vec<KeyChangePayload>>().await?;
+        info!("Found {} key changes", changes.len());
+        Ok(changes)
+    } else {
+        Err(ApiError::from_response(response).await.into())
+    }
+}
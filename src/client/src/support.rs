@@ -1,9 +1,15 @@
 use std::error::Error;
+use std::num::NonZeroU32;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::Aead;
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::{clamp_integer, Scalar};
 use hkdf::Hkdf;
 use rand::RngCore;
 use rand::rngs::OsRng;
-use ring::signature::{UnparsedPublicKey, ED25519};
-use sha2::Sha256;
+use ring::pbkdf2;
+use sha2::{Digest, Sha256, Sha512};
 use x25519_dalek::{PublicKey, StaticSecret};
 use crate::util::{
     INTERMEDIATE_KEY_CONSTANT, 
@@ -11,7 +17,8 @@ use crate::util::{
     RECV_KEY_CONSTANT, 
     RECV_SEND_KEY_CONSTANT, 
     ROOT_KEY_CONSTANT, 
-    SEND_KEY_CONSTANT
+    SEND_KEY_CONSTANT,
+    STORAGE_KEY_CONSTANT
 };
 
 pub struct X25519 {
@@ -86,9 +93,118 @@ pub fn dh_ratchet_update(shared_key: &[u8; 32], root_key: &mut[u8; 32], reverse:
     }
 }
 
-pub fn verify_spk_signature(ikp_public: &[u8], spk: &[u8], spk_sig: &[u8]) -> Result<(), Box<dyn Error>> {
-    let public_key = UnparsedPublicKey::new(&ED25519, ikp_public);
-    if ikp_public.len() == 32 { return Ok(()); }
-    public_key.verify(spk, spk_sig).map_err(|e| format!("Failed to verify signature: {}", e))?;
+/// Checks that the identity key `ikp_public` signed the signed prekey `spk`, so the server cannot
+/// swap in a prekey of its own.
+pub fn verify_spk_signature(ikp_public: &[u8; 32], spk: &[u8; 32], spk_sig: &[u8]) -> Result<(), Box<dyn Error>> {
+    if !xeddsa_verify(ikp_public, spk, spk_sig) {
+        return Err("The signed prekey is not signed by the identity key".into());
+    }
     Ok(())
 }
+
+pub fn storage_key(ik_private: &[u8; 32]) -> Result<[u8; 32], Box<dyn Error>> {
+    let hk = Hkdf::<Sha256>::new(None, ik_private);
+    let mut key = [0u8; 32];
+    
+    hk.expand(STORAGE_KEY_CONSTANT, &mut key)
+        .map_err(|e| format!("Failed to expand storage key: {}", e))?;
+    
+    Ok(key)
+}
+
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| format!("Failed to create cipher: {}", e))?;
+    let mut nonce_bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+        .map_err(|e| format!("Failed to encrypt data: {}", e))?;
+    
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+pub fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if sealed.len() < 12 { return Err("Sealed data is too short".into()); }
+    
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| format!("Failed to create cipher: {}", e))?;
+    
+    let plaintext = cipher.decrypt(Nonce::from_slice(&sealed[..12]), &sealed[12..])
+        .map_err(|e| format!("Failed to decrypt data: {}", e))?;
+    
+    Ok(plaintext)
+}
+
+pub fn passphrase_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<[u8; 32], Box<dyn Error>> {
+    let iterations = NonZeroU32::new(iterations).ok_or("Iteration count must be positive")?;
+    let mut key = [0u8; 32];
+    
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    
+    Ok(key)
+}
+
+/// Verifies an XEdDSA signature made with the X25519 key `public` by [`xeddsa_sign`].
+pub fn xeddsa_verify(public: &[u8; 32], message: &[u8], signature: &[u8]) -> bool {
+    if signature.len() != 64 { return false; }
+    
+    let a = match MontgomeryPoint(*public).to_edwards(0) {
+        Some(a) => a,
+        None => return false,
+    };
+    
+    let r: [u8; 32] = signature[..32].try_into().unwrap();
+    let s = match Option::<Scalar>::from(Scalar::from_canonical_bytes(signature[32..].try_into().unwrap())) {
+        Some(s) => s,
+        None => return false,
+    };
+    
+    let h = Scalar::from_bytes_mod_order_wide(&Sha512::new()
+        .chain_update(r)
+        .chain_update(a.compress().as_bytes())
+        .chain_update(message)
+        .finalize()
+        .into());
+    
+    EdwardsPoint::vartime_double_scalar_mul_basepoint(&-h, &a, &s).compress().to_bytes() == r
+}
+
+/// Signs `message` with an X25519 private key using XEdDSA, so the identity key can prove
+/// ownership without a separate Ed25519 key pair.
+pub fn xeddsa_sign(private: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let mut a = Scalar::from_bytes_mod_order(clamp_integer(*private));
+    let mut public = EdwardsPoint::mul_base(&a).compress().to_bytes();
+    if public[31] & 0x80 != 0 {
+        a = -a;
+        public[31] &= 0x7F;
+    }
+    
+    let mut z = [0u8; 64];
+    OsRng.fill_bytes(&mut z);
+    
+    let r = Scalar::from_bytes_mod_order_wide(&Sha512::new()
+        .chain_update([0xFEu8])
+        .chain_update([0xFFu8; 31])
+        .chain_update(a.as_bytes())
+        .chain_update(message)
+        .chain_update(z)
+        .finalize()
+        .into());
+    let r_point = EdwardsPoint::mul_base(&r).compress().to_bytes();
+    
+    let h = Scalar::from_bytes_mod_order_wide(&Sha512::new()
+        .chain_update(r_point)
+        .chain_update(public)
+        .chain_update(message)
+        .finalize()
+        .into());
+    let s = r + h * a;
+    
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&r_point);
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
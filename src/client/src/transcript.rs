@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use crate::account::Account;
+use crate::file::{init_load_user, valid_name, LocalArchive, MessageHistory, SessionKey};
+use crate::message::Message;
+use crate::util::TRANSCRIPT_ARCHIVE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Text,
+    Archive,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Text => "txt",
+            ExportFormat::Archive => "e2ee",
+        }
+    }
+}
+
+/// One conversation as seen by `account`. `Message::sender` is true for messages `account` sent.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transcript {
+    pub account: String,
+    pub target: String,
+    pub messages: Vec<Message>,
+}
+
+impl Transcript {
+    pub fn load(account: &Account, target: &str) -> Result<Self, Box<dyn Error>> {
+        let (_, messages) = MessageHistory::since(account, target, 0)?;
+
+        Ok(Self {
+            account: account.name().to_string(),
+            target: target.to_string(),
+            messages,
+        })
+    }
+
+    pub fn load_all(account: &Account) -> Result<Vec<Self>, Box<dyn Error>> {
+        init_load_user(account.name()).iter()
+            .map(|target| Self::load(account, target))
+            .collect()
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!("Conversation between {} and {}\n", self.account, self.target);
+
+        for message in &self.messages {
+            let (from, to) = if message.sender {
+                (&self.account, &self.target)
+            } else {
+                (&self.target, &self.account)
+            };
+            text += &format!("[{}] #{} {} -> {}: {}\n", message.timestamp(), message.id, from, to, message.text);
+        }
+
+        text
+    }
+
+    /// Writes one conversation, or every conversation when `target` is `None`, into `folder`.
+    /// The passphrase is only used by the archive format.
+    pub fn export(
+        account: &Account,
+        target: Option<&str>,
+        format: ExportFormat,
+        passphrase: &str,
+        folder: &Path,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let transcripts = match target {
+            Some(target) => vec![Self::load(account, target)?],
+            None => Self::load_all(account)?,
+        };
+
+        fs::create_dir_all(folder)?;
+        let path = folder.join(format!("{}-{}.{}", account.name(), target.unwrap_or("all"), format.extension()));
+
+        match format {
+            ExportFormat::Json => {
+                serde_json::to_writer_pretty(File::create(&path)?, &transcripts)?;
+            },
+            ExportFormat::Text => {
+                let text: Vec<String> = transcripts.iter().map(|t| t.to_text()).collect();
+                fs::write(&path, text.join("\n"))?;
+            },
+            ExportFormat::Archive => {
+                if passphrase.is_empty() { return Err("A passphrase is required for archives".into()); }
+                LocalArchive::seal(TRANSCRIPT_ARCHIVE, passphrase, &serde_json::to_vec(&transcripts)?)?.save(&path)?;
+            },
+        }
+
+        info!("Exported {} conversations to {:?}", transcripts.len(), path);
+        Ok(path)
+    }
+
+    /// Imports an archive of this account's conversations into its history, skipping messages
+    /// that are already stored and conversations without a session here. The rest is merged as
+    /// a restored backup is, see [`MessageHistory::merge`], and gets fresh local ids.
+    pub fn import(account: &Account, path: &Path, passphrase: &str) -> Result<usize, Box<dyn Error>> {
+        let plaintext = LocalArchive::load(path)?.open(TRANSCRIPT_ARCHIVE, passphrase)?;
+        let transcripts: Vec<Transcript> = serde_json::from_slice(&plaintext)?;
+        let mut count = 0;
+
+        // `sender` is relative to the exporting account, and `target` becomes a folder name.
+        for transcript in &transcripts {
+            if transcript.account != account.name() {
+                return Err(format!("The archive holds conversations of {}, not {}", transcript.account, account.name()).into());
+            }
+            if !valid_name(&transcript.target) {
+                return Err(format!("Invalid conversation name {:?} in archive", transcript.target).into());
+            }
+        }
+
+        for transcript in transcripts {
+            if !SessionKey::exists(account.name(), &transcript.target)? {
+                warn!("Skipped conversation with {}, there is no session with it", transcript.target);
+                continue;
+            }
+
+            count += MessageHistory::merge(account, &transcript.target, transcript.messages)?;
+        }
+
+        info!("Imported {} messages from {:?}", count, path);
+        Ok(count)
+    }
+}
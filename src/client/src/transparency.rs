@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::error::Error;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::util::TREE_HEAD_CONTEXT;
+
+type Hash = [u8; 32];
+
+/// A signed root of the key transparency log at `size` entries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeHead {
+    pub size: u64,
+    pub root: String,
+    pub timestamp: i64,
+    pub signature: String,
+    pub key: String,
+}
+
+/// The proof the server sends with a prekey bundle that its identity key is in the log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyProof {
+    pub index: u64,
+    pub version: i32,
+    pub proof: Vec<String>,
+    pub head: TreeHead,
+}
+
+fn leaf_hash(account: &str, ik_public: &str, version: i32) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update(format!("{}:{}:{}", account, ik_public, version).as_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn decode_path(path: &[String]) -> Result<Vec<Hash>, Box<dyn Error>> {
+    path.iter()
+        .map(|hash| Ok(<[u8; 32]>::try_from(hex::decode(hash)?).map_err(|_| "Malformed proof hash")?))
+        .collect()
+}
+
+fn decode_root(head: &TreeHead) -> Result<Hash, Box<dyn Error>> {
+    Ok(<[u8; 32]>::try_from(hex::decode(&head.root)?).map_err(|_| "Malformed tree root")?)
+}
+
+/// Checks an RFC 9162 inclusion proof of `leaf` at `index` in the tree of `size` leaves.
+fn verify_inclusion(leaf: Hash, index: u64, size: u64, path: &[Hash], root: &Hash) -> bool {
+    if index >= size { return false; }
+
+    let (mut node, mut last) = (index, size - 1);
+    let mut hash = leaf;
+    for p in path {
+        if last == 0 { return false; }
+        if node & 1 == 1 || node == last {
+            hash = node_hash(p, &hash);
+            while node & 1 == 0 && node != 0 {
+                node >>= 1;
+                last >>= 1;
+            }
+        } else {
+            hash = node_hash(&hash, p);
+        }
+        node >>= 1;
+        last >>= 1;
+    }
+    last == 0 && hash == *root
+}
+
+/// Checks an RFC 9162 consistency proof that the tree of `first` leaves is a prefix of the tree
+/// of `second` leaves.
+fn verify_consistency(first: u64, second: u64, first_root: &Hash, second_root: &Hash, path: &[Hash]) -> bool {
+    if first == second { return path.is_empty() && first_root == second_root; }
+    if first == 0 { return path.is_empty(); }
+    if first > second { return false; }
+
+    let mut path = path.to_vec();
+    if first.is_power_of_two() {
+        path.insert(0, *first_root);
+    }
+    let Some((start, rest)) = path.split_first() else { return false; };
+
+    let (mut node, mut last) = (first - 1, second - 1);
+    while node & 1 == 1 {
+        node >>= 1;
+        last >>= 1;
+    }
+    let (mut fr, mut sr) = (*start, *start);
+    for c in rest {
+        if last == 0 { return false; }
+        if node & 1 == 1 || node == last {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while node & 1 == 0 && node != 0 {
+                node >>= 1;
+                last >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        node >>= 1;
+        last >>= 1;
+    }
+    fr == *first_root && sr == *second_root && last == 0
+}
+
+/// What an account remembers of the log: the key that signs tree heads, the largest tree head
+/// it has verified and the newest key version it has seen for each peer.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LogState {
+    key: Option<String>,
+    head: Option<TreeHead>,
+    #[serde(default)]
+    versions: HashMap<String, (i32, String)>,
+}
+
+impl LogState {
+    pub fn head(&self) -> Option<&TreeHead> {
+        self.head.as_ref()
+    }
+
+    /// Checks the signature of `head` against the pinned log key. `LOG_PUBLIC_KEY` pins the key
+    /// up front, otherwise the first key seen is trusted from then on.
+    pub fn verify_head(&mut self, head: &TreeHead) -> Result<(), Box<dyn Error>> {
+        let pinned = std::env::var("LOG_PUBLIC_KEY").ok().or(self.key.clone());
+        if pinned.as_ref().is_some_and(|key| *key != head.key) {
+            return Err("The key transparency log is signed by an unknown key".into());
+        }
+
+        let message = format!("{}:{}:{}:{}", TREE_HEAD_CONTEXT, head.size, head.root, head.timestamp);
+        UnparsedPublicKey::new(&ED25519, hex::decode(&head.key)?)
+            .verify(message.as_bytes(), &hex::decode(&head.signature)?)
+            .map_err(|_| "Invalid tree head signature")?;
+
+        self.key = Some(head.key.clone());
+        Ok(())
+    }
+
+    /// Checks that `ik_public` is in the tree of `proof.head` as version `proof.version` of
+    /// `account`, and that the version has not gone backwards since the last bundle.
+    pub fn verify_key(&mut self, account: &str, ik_public: &str, proof: &KeyProof) -> Result<(), Box<dyn Error>> {
+        let leaf = leaf_hash(account, ik_public, proof.version);
+        let root = decode_root(&proof.head)?;
+        if !verify_inclusion(leaf, proof.index, proof.head.size, &decode_path(&proof.proof)?, &root) {
+            return Err(format!("The identity key of {} is not in the key transparency log", account).into());
+        }
+
+        match self.versions.get(account) {
+            Some((version, _)) if *version > proof.version => {
+                return Err(format!("The server returned an outdated identity key of {}", account).into());
+            },
+            Some((version, key)) if *version == proof.version && key != ik_public => {
+                return Err(format!("The server returned two identity keys of {} as version {}", account, version).into());
+            },
+            _ => {},
+        }
+
+        self.versions.insert(account.to_string(), (proof.version, ik_public.to_string()));
+        Ok(())
+    }
+
+    /// Checks that `head` and the stored head describe the same history, given the consistency
+    /// proof from the smaller of the two to the larger, and keeps the larger one.
+    pub fn verify_consistency(&mut self, head: &TreeHead, path: &[String]) -> Result<(), Box<dyn Error>> {
+        if let Some(known) = &self.head {
+            let (first, second) = if known.size <= head.size { (known, head) } else { (head, known) };
+            if !verify_consistency(first.size, second.size, &decode_root(first)?, &decode_root(second)?, &decode_path(path)?) {
+                return Err(format!("The key transparency log at {} entries does not extend the one at {}",
+                    second.size, first.size).into());
+            }
+            if known.size >= head.size { return Ok(()); }
+        }
+
+        self.head = Some(head.clone());
+        Ok(())
+    }
+}
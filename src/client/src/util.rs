@@ -7,4 +7,33 @@ pub const RECV_SEND_KEY_CONSTANT: &[u8] = b"recv_send_key";
 pub const ROOT_KEY_CONSTANT: &[u8] = b"root_key";
 pub const INTERMEDIATE_KEY_CONSTANT: &[u8] = b"intermediate_key";
 
-pub const MAX_TIME_UPDATE: i64 = 5;
\ No newline at end of file
+pub const MAX_TIME_UPDATE: i64 = 5;
+/// How many messages read out of order a session remembers above the ones read in order.
+pub const MAX_SEEN_AHEAD: usize = 256;
+
+pub const STORAGE_KEY_CONSTANT: &[u8] = b"storage_key";
+pub const HISTORY_PAGE_SIZE: usize = 50;
+pub const MAX_SEARCH_RESULTS: usize = 50;
+
+pub const ARCHIVE_VERSION: u32 = 1;
+pub const ARCHIVE_ITERATIONS: u32 = 600_000;
+/// Archives come from elsewhere, so they cannot make opening them arbitrarily slow.
+pub const MAX_ARCHIVE_ITERATIONS: u32 = 10_000_000;
+pub const TRANSCRIPT_ARCHIVE: &str = "transcript";
+pub const BACKUP_ARCHIVE: &str = "backup";
+
+pub const LOGIN_CONTEXT: &str = "e2ee-login";
+pub const UPDATE_CONTEXT: &str = "e2ee-update";
+pub const TREE_HEAD_CONTEXT: &str = "e2ee-tree-head";
+
+pub const POLL_INTERVAL: u64 = 5;
+/// Seconds the time a sender gives may differ from the time the server received the message
+/// before the chat points it out.
+pub const MAX_TIMESTAMP_SKEW: i64 = 300;
+pub const PUSH_MIN_BACKOFF: u64 = 1;
+pub const PUSH_MAX_BACKOFF: u64 = 60;
+/// Events the daemon holds for a subscriber that reads slower than they arrive.
+pub const DAEMON_EVENT_BUFFER: usize = 256;
+/// Messages the daemon keeps for `receive`. They are in the history either way, so past this the
+/// oldest are only dropped from the inbox.
+pub const DAEMON_INBOX_SIZE: usize = 1000;
\ No newline at end of file
@@ -1,34 +1,150 @@
 use clap::{Parser, Subcommand};
 use dotenv::from_path;
 use std::error::Error;
-use std::path::Path;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 #[derive(Parser)]
 #[command(name = "App")]
 #[command(about = "An application with server and client modes", long_about = None)]
 struct Cli {
+    /// Environment file to load instead of looking for one
+    #[arg(long, global = true, env = "E2EE_ENV_FILE")]
+    env_file: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Server,
+    Server {
+        #[command(subcommand)]
+        command: Option<ServerCommands>,
+    },
     Client,
+    /// Use the client from the command line, printing JSON
+    Cli {
+        /// Local account to act as
+        #[arg(long, env = "E2EE_ACCOUNT")]
+        account: Option<String>,
+        #[command(subcommand)]
+        command: client::CliCommand,
+    },
+    /// Keep an account signed in and serve JSON-RPC to local tools on a Unix socket
+    Daemon {
+        #[arg(long, env = "E2EE_ACCOUNT")]
+        account: String,
+        /// Socket to listen on, `daemon.sock` in the account folder by default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Pack an account's identity, prekeys and sessions into a passphrase-protected archive
+    Backup {
+        account: String,
+        output: PathBuf,
+        /// Also include the local message history
+        #[arg(long)]
+        history: bool,
+    },
+    /// Restore an account from an archive created by `backup`
+    Restore {
+        input: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServerCommands {
+    /// Apply pending database migrations and exit
+    Migrate,
+}
+
+/// Loads `env_file` if given. Otherwise loads `.env` from the working directory, or the
+/// `src/<mode>/.env` of a source checkout. Without any file the process environment is used as is.
+fn load_env(env_file: Option<&Path>, mode: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = env_file {
+        from_path(path).map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+        return Ok(());
+    }
+    
+    let checkout = Path::new("./src").join(mode).join(".env");
+    for path in [Path::new(".env"), checkout.as_path()] {
+        if path.exists() {
+            from_path(path).map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The archive passphrase from `E2EE_PASSPHRASE`, or else typed on the terminal. It is never an
+/// argument, since other users can read those from the process list. A new one is asked for twice.
+fn passphrase(confirm: bool) -> Result<String, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var("E2EE_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    
+    let passphrase = prompt("Passphrase: ")?;
+    if confirm && prompt("Repeat the passphrase: ")? != passphrase {
+        return Err("The passphrases do not match".into());
+    }
+    Ok(passphrase)
+}
+
+/// Reads a line from stdin with the terminal echo turned off through `stty`.
+fn prompt(label: &str) -> Result<String, Box<dyn Error>> {
+    let terminal = io::stdin().is_terminal();
+    if terminal {
+        eprint!("{}", label);
+        io::stderr().flush()?;
+        let hidden = Command::new("stty").arg("-echo").status().is_ok_and(|status| status.success());
+        if !hidden {
+            return Err("Could not hide the passphrase, set E2EE_PASSPHRASE instead".into());
+        }
+    }
+    
+    let mut line = String::new();
+    let read = io::stdin().read_line(&mut line);
+    if terminal {
+        Command::new("stty").arg("echo").status()?;
+        eprintln!();
+    }
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+    let env_file = cli.env_file.as_deref();
 
     match &cli.command {
-        Commands::Server => {
-            from_path(Path::new("./src/server/.env")).expect("Failed to load .env file");
-            server::start()?
+        Commands::Server { command } => {
+            load_env(env_file, "server")?;
+            match command {
+                Some(ServerCommands::Migrate) => server::migrate()?,
+                None => server::start()?,
+            }
         }
         Commands::Client => {
-            from_path(Path::new("./src/client/.env")).expect("Failed to load .env file");
+            load_env(env_file, "client")?;
             client::start()?
         }
+        Commands::Cli { account, command } => {
+            load_env(env_file, "client")?;
+            client::cli(account.as_deref(), command.clone())?
+        }
+        Commands::Daemon { account, socket } => {
+            load_env(env_file, "client")?;
+            client::daemon(account, socket.as_deref())?
+        }
+        Commands::Backup { account, output, history } => {
+            load_env(env_file, "client")?;
+            client::backup(account, output, &passphrase(true)?, *history)?
+        }
+        Commands::Restore { input } => {
+            load_env(env_file, "client")?;
+            client::restore(input, &passphrase(false)?)?
+        }
     };
     Ok(())
 }
@@ -0,0 +1,3 @@
+create index if not exists chat_target_account on chat (target, account);
+create index if not exists request_target on request (target);
+create index if not exists key_change_account on key_change (account);
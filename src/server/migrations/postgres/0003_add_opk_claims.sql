@@ -0,0 +1,10 @@
+-- Which one-time prekey of an account went to whom and when.
+create table if not exists opk_claim (
+    id serial primary key,
+    account varchar(255) not null,
+    opk_id int not null,
+    claimant varchar(255) not null,
+    timestamp bigint not null
+);
+
+create index if not exists opk_claim_account on opk_claim (account);
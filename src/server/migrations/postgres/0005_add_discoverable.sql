@@ -0,0 +1,2 @@
+-- Accounts are only found by an exact name unless they opt in to prefix search.
+alter table "user" add column if not exists discoverable boolean not null default false;
@@ -0,0 +1,7 @@
+-- Accounts that `account` no longer accepts session requests or messages from.
+create table if not exists blocked (
+    account varchar(255) not null,
+    target varchar(255) not null,
+    timestamp bigint not null,
+    primary key (account, target)
+);
@@ -0,0 +1,11 @@
+-- When the server received each message and its place in the conversation, counted by `conversation`.
+alter table chat add column if not exists received bigint not null default 0;
+alter table chat add column if not exists seq bigint not null default 0;
+
+-- The last sequence number handed out between two accounts, stored with the smaller name as `account`.
+create table if not exists conversation (
+    account varchar(255) not null,
+    target varchar(255) not null,
+    seq bigint not null,
+    primary key (account, target)
+);
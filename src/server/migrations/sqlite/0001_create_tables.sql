@@ -0,0 +1,51 @@
+create table if not exists "user" (
+    account varchar(255) primary key,
+    ik_public char(64) not null,
+    spk_public char(64) not null,
+    spk_signature char(128) not null,
+    recovery char(64)
+);
+
+create table if not exists opk (
+    opk char(64) not null,
+    account varchar(255),
+    id int not null,
+    primary key (account, id)
+);
+
+create table if not exists request (
+    account varchar(255),
+    target varchar(255),
+    ek char(64) not null,
+    ikp char(64) not null,
+    id int not null,
+    leased_until bigint,
+    primary key (account, target)
+);
+
+create table if not exists contact (
+    account varchar(255),
+    target varchar(255),
+    primary key (account, target)
+);
+
+create table if not exists key_change (
+    id integer primary key autoincrement,
+    account varchar(255) not null,
+    target varchar(255) not null,
+    ik_public char(64) not null,
+    timestamp bigint not null
+);
+
+create table if not exists chat (
+    id integer primary key autoincrement,
+    account varchar(255) not null,
+    target varchar(255) not null,
+    message text not null,
+    timestamp bigint not null,
+    leased_until bigint
+);
+
+create index if not exists chat_target_account on chat (target, account);
+create index if not exists request_target on request (target);
+create index if not exists key_change_account on key_change (account);
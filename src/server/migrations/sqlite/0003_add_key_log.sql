@@ -0,0 +1,15 @@
+-- Append-only log of every identity key an account has registered. `position` is the leaf index
+-- in the key transparency tree and has no gaps.
+create table if not exists key_log (
+    position bigint primary key,
+    account varchar(255) not null,
+    ik_public char(64) not null,
+    version int not null,
+    unique (account, version)
+);
+
+-- Accounts registered before the log existed enter it with their current key.
+insert into key_log (position, account, ik_public, version)
+select (select count(*) from key_log) + row_number() over (order by account) - 1, account, ik_public, 1
+from "user"
+where account not in (select account from key_log);
@@ -0,0 +1,2 @@
+-- Accounts are only found by an exact name unless they opt in to prefix search.
+alter table "user" add column discoverable boolean not null default false;
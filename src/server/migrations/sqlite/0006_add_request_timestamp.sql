@@ -0,0 +1,6 @@
+-- When each session request was made, so stale ones can expire. Requests from before this
+-- migration count from the time it runs.
+alter table request add column timestamp bigint not null default 0;
+update request set timestamp = cast(strftime('%s', 'now') as integer) where timestamp = 0;
+
+create index if not exists request_timestamp on request (timestamp);
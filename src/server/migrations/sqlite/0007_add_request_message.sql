@@ -0,0 +1,2 @@
+-- The first message of the session, encrypted under the keys of the request.
+alter table request add column message text;
@@ -0,0 +1,4 @@
+-- Messages are numbered per sender from now on, so `conversation` keeps one row per direction.
+-- Both directions continue above the numbers handed out so far, which clients may have read.
+insert or ignore into conversation (account, target, seq)
+select target, account, seq from conversation;
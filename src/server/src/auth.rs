@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::{
+    async_trait,
+    Extension,
+    Json,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts},
+};
+use curve25519_dalek::{
+    edwards::EdwardsPoint,
+    montgomery::MontgomeryPoint,
+    scalar::Scalar
+};
+use log::{info, warn};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use crate::error::{validate_account, validate_key, ServerError};
+use crate::storage::Db;
+
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+const TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+const LOGIN_CONTEXT: &str = "e2ee-login";
+
+/// Pending login challenges and the bearer tokens issued for them. Both live only in memory,
+/// so restarting the server signs every client out. Challenges are keyed by their value, so
+/// several logins of one account can be in flight without replacing each other's challenge.
+#[derive(Default)]
+pub struct AuthState {
+    challenges: Mutex<HashMap<String, (String, Instant)>>,
+    tokens: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl AuthState {
+    fn random_hex() -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        hex::encode(bytes)
+    }
+
+    fn issue_challenge(&self, account: &str) -> String {
+        let challenge = Self::random_hex();
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, (_, expiry)| *expiry > Instant::now());
+        challenges.insert(challenge.clone(), (account.to_string(), Instant::now() + CHALLENGE_TTL));
+        challenge
+    }
+
+    /// Consumes `challenge` if it was issued to `account`, so every challenge can be answered once.
+    /// Key updates answer the same challenges as logins, under another signing context.
+    pub fn take_challenge(&self, account: &str, challenge: &str) -> bool {
+        match self.challenges.lock().unwrap().remove(challenge) {
+            Some((expected, expiry)) => expected == account && expiry > Instant::now(),
+            None => false,
+        }
+    }
+
+    fn issue_token(&self, account: &str) -> String {
+        let token = Self::random_hex();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, (_, expiry)| *expiry > Instant::now());
+        tokens.insert(token.clone(), (account.to_string(), Instant::now() + TOKEN_TTL));
+        token
+    }
+
+    pub fn account(&self, token: &str) -> Option<String> {
+        match self.tokens.lock().unwrap().get(token) {
+            Some((account, expiry)) if *expiry > Instant::now() => Some(account.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The account behind the bearer token of a request. Handlers take the caller's identity from
+/// here and never from the request body.
+pub struct AuthUser(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = ServerError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let token = parts.headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(ServerError::Unauthorized)?;
+
+        parts.extensions.get::<Arc<AuthState>>()
+            .and_then(|auth| auth.account(token))
+            .map(AuthUser)
+            .ok_or(ServerError::Unauthorized)
+    }
+}
+
+pub fn login_message(account: &str, challenge: &str) -> Vec<u8> {
+    format!("{}:{}:{}", LOGIN_CONTEXT, account, challenge).into_bytes()
+}
+
+/// Verifies an XEdDSA signature made with the X25519 identity key `public`.
+pub fn xeddsa_verify(public: &[u8; 32], message: &[u8], signature: &[u8]) -> bool {
+    if signature.len() != 64 { return false; }
+
+    let a = match MontgomeryPoint(*public).to_edwards(0) {
+        Some(a) => a,
+        None => return false,
+    };
+
+    let r: [u8; 32] = signature[..32].try_into().unwrap();
+    let s = match Option::<Scalar>::from(Scalar::from_canonical_bytes(signature[32..].try_into().unwrap())) {
+        Some(s) => s,
+        None => return false,
+    };
+
+    let h = Scalar::from_bytes_mod_order_wide(&Sha512::new()
+        .chain_update(r)
+        .chain_update(a.compress().as_bytes())
+        .chain_update(message)
+        .finalize()
+        .into());
+
+    EdwardsPoint::vartime_double_scalar_mul_basepoint(&-h, &a, &s).compress().to_bytes() == r
+}
+
+#[derive(Deserialize)]
+pub struct ChallengePayload { account: String }
+
+#[derive(Serialize)]
+pub struct ChallengeResponse { challenge: String }
+
+#[derive(Deserialize)]
+pub struct LoginPayload {
+    account: String,
+    challenge: String,
+    signature: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    token: String,
+    expires_in: u64,
+}
+
+#[axum::debug_handler]
+pub async fn challenge(
+    Extension(db): Extension<Db>,
+    Extension(auth): Extension<Arc<AuthState>>,
+    Json(payload): Json<ChallengePayload>
+) -> Result<Json<ChallengeResponse>, ServerError> {
+    validate_account(&payload.account)?;
+
+    if db.find_user(&payload.account).await?.is_some() {
+        Ok(Json(ChallengeResponse { challenge: auth.issue_challenge(&payload.account) }))
+    } else {
+        warn!("[Auth] <{}> does not exist", payload.account);
+        Err(ServerError::UserNotFound(payload.account))
+    }
+}
+
+#[axum::debug_handler]
+pub async fn login(
+    Extension(db): Extension<Db>,
+    Extension(auth): Extension<Arc<AuthState>>,
+    Json(payload): Json<LoginPayload>
+) -> Result<Json<LoginResponse>, ServerError> {
+    validate_account(&payload.account)?;
+    validate_key("signature", &payload.signature, 64)?;
+
+    if !auth.take_challenge(&payload.account, &payload.challenge) {
+        warn!("[Auth] <{}> answered an unknown or expired challenge", payload.account);
+        return Err(ServerError::Unauthorized);
+    }
+
+    let ik_public = db.find_user(&payload.account).await?
+        .and_then(|user| hex::decode(user.ik_public.trim()).ok())
+        .and_then(|key| <[u8; 32]>::try_from(key).ok());
+    let signature = hex::decode(&payload.signature).unwrap_or_default();
+
+    match ik_public {
+        Some(key) if xeddsa_verify(&key, &login_message(&payload.account, &payload.challenge), &signature) => {
+            info!("[Auth] <{}> logged in", payload.account);
+            Ok(Json(LoginResponse {
+                token: auth.issue_token(&payload.account),
+                expires_in: TOKEN_TTL.as_secs(),
+            }))
+        },
+        _ => {
+            warn!("[Auth] <{}> failed to log in", payload.account);
+            Err(ServerError::Unauthorized)
+        }
+    }
+}
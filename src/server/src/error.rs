@@ -0,0 +1,155 @@
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+use serde::Serialize;
+
+/// Every way a request can fail. The `code` of each variant is part of the API and must not
+/// change, clients match on it.
+#[derive(Debug)]
+pub enum ServerError {
+    InvalidAccount(String),
+    InvalidKey(&'static str),
+    InvalidTimestamp(i64),
+    InvalidRequest(String),
+    Unauthorized,
+    KeyProofRequired,
+    UserNotFound(String),
+    NoPrekeys(String),
+    RequestNotFound(String),
+    RequestExists(String),
+    MessageTooLarge(usize),
+    QueueFull(String),
+    RateLimited(u64),
+    Database(sqlx::Error),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServerError::InvalidAccount(_)
+            | ServerError::InvalidKey(_)
+            | ServerError::InvalidTimestamp(_)
+            | ServerError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ServerError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServerError::KeyProofRequired => StatusCode::FORBIDDEN,
+            ServerError::UserNotFound(_)
+            | ServerError::NoPrekeys(_)
+            | ServerError::RequestNotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::RequestExists(_) => StatusCode::CONFLICT,
+            ServerError::MessageTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            // A full queue drains once the recipient reads it, so the sender retries later as
+            // it would when rate limited. The code tells the two apart.
+            ServerError::QueueFull(_)
+            | ServerError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ServerError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::InvalidAccount(_) => "invalid_account",
+            ServerError::InvalidKey(_) => "invalid_key",
+            ServerError::InvalidTimestamp(_) => "invalid_timestamp",
+            ServerError::InvalidRequest(_) => "invalid_request",
+            ServerError::Unauthorized => "unauthorized",
+            ServerError::KeyProofRequired => "key_proof_required",
+            ServerError::UserNotFound(_) => "user_not_found",
+            ServerError::NoPrekeys(_) => "no_prekeys",
+            ServerError::RequestNotFound(_) => "request_not_found",
+            ServerError::RequestExists(_) => "request_exists",
+            ServerError::MessageTooLarge(_) => "message_too_large",
+            ServerError::QueueFull(_) => "queue_full",
+            ServerError::RateLimited(_) => "rate_limited",
+            ServerError::Database(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ServerError::InvalidAccount(account) => format!("{:?} is not a valid account name", account),
+            ServerError::InvalidKey(field) => format!("{} is not a valid hex key", field),
+            ServerError::InvalidTimestamp(timestamp) => format!("{} is not a valid timestamp", timestamp),
+            ServerError::InvalidRequest(reason) => reason.clone(),
+            ServerError::Unauthorized => "Missing, expired or invalid credentials".to_string(),
+            ServerError::KeyProofRequired => "Replacing registered keys needs a signed proof or the recovery code".to_string(),
+            ServerError::UserNotFound(account) => format!("{} does not exist", account),
+            ServerError::NoPrekeys(account) => format!("{} does not have any one-time prekeys", account),
+            ServerError::RequestNotFound(account) => format!("There is no session request from {}", account),
+            ServerError::RequestExists(account) => format!("A session request to {} is already pending", account),
+            ServerError::MessageTooLarge(limit) => format!("Messages are limited to {} bytes", limit),
+            ServerError::QueueFull(account) => format!("The queue of {} is full", account),
+            ServerError::RateLimited(retry) => format!("Too many requests, retry in {} seconds", retry),
+            // Database details stay in the server log.
+            ServerError::Database(_) => "Internal server error".to_string(),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ServerError {
+    fn from(error: sqlx::Error) -> Self {
+        ServerError::Database(error)
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        if let ServerError::Database(e) = &self {
+            warn!("[Database] {}", e);
+        }
+
+        (self.status(), Json(ErrorBody { code: self.code(), message: self.message() })).into_response()
+    }
+}
+
+const MAX_ACCOUNT_LENGTH: usize = 64;
+/// The size of the account columns, which bounds names registered before the naming rule.
+const MAX_STORED_ACCOUNT_LENGTH: usize = 255;
+/// Timestamps are seconds since the epoch and may run ahead of the server clock by this much.
+const MAX_CLOCK_SKEW: i64 = 24 * 60 * 60;
+
+/// Checks a name that refers to an account. Accounts registered before the naming rule keep
+/// their names, so anything that fits the account columns is accepted.
+pub fn validate_account(account: &str) -> Result<(), ServerError> {
+    if !account.is_empty() && account.chars().count() <= MAX_STORED_ACCOUNT_LENGTH {
+        Ok(())
+    } else {
+        Err(ServerError::InvalidAccount(account.to_string()))
+    }
+}
+
+/// New accounts are named with 1 to 64 letters, digits, `-`, `_` or `.`, and not with dots
+/// only, which clients would take for the current or parent folder.
+pub fn validate_new_account(account: &str) -> Result<(), ServerError> {
+    let valid = !account.is_empty()
+        && account.len() <= MAX_ACCOUNT_LENGTH
+        && account.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && !account.chars().all(|c| c == '.');
+
+    if valid { Ok(()) } else { Err(ServerError::InvalidAccount(account.to_string())) }
+}
+
+/// Checks that `value` is hex encoding exactly `bytes` bytes.
+pub fn validate_key(field: &'static str, value: &str, bytes: usize) -> Result<(), ServerError> {
+    if value.len() == bytes * 2 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(ServerError::InvalidKey(field))
+    }
+}
+
+pub fn validate_timestamp(timestamp: i64) -> Result<(), ServerError> {
+    if timestamp > 0 && timestamp <= chrono::Local::now().timestamp() + MAX_CLOCK_SKEW {
+        Ok(())
+    } else {
+        Err(ServerError::InvalidTimestamp(timestamp))
+    }
+}
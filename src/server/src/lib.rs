@@ -1,20 +1,54 @@
+mod auth;
+mod error;
+mod push;
+mod ratelimit;
+mod storage;
+mod transparency;
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use axum::{
     Extension, 
     Json, 
     Router,
+    extract::DefaultBodyLimit,
     http::StatusCode,
-    response::IntoResponse,
-    routing::post
+    middleware,
+    routing::{get, post}
 };
 use chrono::Local;
 use fern::Dispatch;
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use sqlx::{
-    PgPool, 
-    postgres::PgPoolOptions
-};
+use sha2::{Digest, Sha256};
+use crate::auth::{xeddsa_verify, AuthState, AuthUser};
+use crate::error::{validate_account, validate_key, validate_new_account, validate_timestamp, ServerError};
+use crate::push::{PushEvent, PushHub};
+use crate::ratelimit::RateLimiter;
+use crate::storage::{Db, MessageRecord, OpkRecord, PendingRequestRecord, RequestRecord, UserRecord};
+use crate::transparency::{KeyLog, KeyProof};
+
+const UPDATE_CONTEXT: &str = "e2ee-update";
+/// How long a fetched message or request stays reserved for the client before it is delivered again.
+const LEASE_MILLIS: i64 = 30_000;
+const MAX_PAGE_SIZE: i64 = 100;
+const MAX_BODY_BYTES: usize = 256 * 1024;
+const MAX_MESSAGE_BYTES: usize = 128 * 1024;
+/// Undelivered messages a recipient may have queued in total and from any single sender, so one
+/// sender cannot fill the whole queue.
+const MAX_QUEUED_MESSAGES: i64 = 1000;
+const MAX_QUEUED_PER_SENDER: i64 = 200;
+const MAX_PENDING_REQUESTS: i64 = 100;
+/// Session requests nobody accepted within a week are deleted.
+const REQUEST_EXPIRY_SECS: i64 = 7 * 24 * 60 * 60;
+const REQUEST_PURGE_INTERVAL_SECS: u64 = 10 * 60;
+const MAX_OPKS: usize = 200;
+const MAX_SEARCH_RESULTS: i64 = 20;
+/// Shorter prefixes would let a client page through most of the discoverable directory.
+const MIN_PREFIX_LENGTH: usize = 3;
 
 #[derive(Serialize, Deserialize)]
 pub struct OPKPayload {
@@ -29,13 +63,79 @@ pub struct CreatePayload {
     spk_public: String,
     spk_signature: String,
     opk: Vec<OPKPayload>,
+    /// SHA-256 of the recovery code that may replace these keys later.
+    #[serde(default)]
+    recovery: Option<String>,
+    /// XEdDSA signature of the currently registered identity key over the new keys, see
+    /// `update_message`.
+    #[serde(default)]
+    proof: Option<String>,
+    /// A login challenge issued to the account, which the proof covers so it cannot be replayed.
+    #[serde(default)]
+    challenge: Option<String>,
+    /// The recovery code registered with the current keys, for when the old identity key is lost.
+    #[serde(default)]
+    recovery_code: Option<String>,
+    /// Whether others may find the account by a prefix of its name. An update keeps the current
+    /// setting when this is left out.
+    #[serde(default)]
+    discoverable: Option<bool>,
 }
 
 #[derive(Deserialize)]
 struct NormalPayload { target: String, } 
 
+/// Query of a paginated endpoint. `after` is the cursor returned with the previous page.
+#[derive(Deserialize)]
+struct PagePayload<T> {
+    #[serde(default)]
+    after: Option<T>,
+    #[serde(default)]
+    limit: Option<i64>,
+}
+
+impl<T> PagePayload<T> {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// One page of results. `next` is only set when the page is full and more may follow.
+#[derive(Serialize)]
+struct Page<T, C> {
+    items: Vec<T>,
+    next: Option<C>,
+}
+
+impl<T, C> Page<T, C> {
+    fn new(items: Vec<T>, limit: i64, cursor: impl Fn(&T) -> C) -> Self {
+        let next = if items.len() as i64 == limit { items.last().map(cursor) } else { None };
+        Self { items, next }
+    }
+}
+
+/// Looks up `target` by its exact name, or with `prefix` among the accounts that opted in to
+/// discovery.
 #[derive(Deserialize)]
-struct SearchPayload { account:String, target: String, } 
+struct SearchPayload {
+    target: String,
+    #[serde(default)]
+    prefix: bool,
+    #[serde(flatten)]
+    page: PagePayload<String>,
+}
+
+#[derive(Deserialize)]
+struct DiscoverablePayload {
+    #[serde(default)]
+    discoverable: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct DiscoverableResponse {
+    discoverable: bool,
+}
+
 
 #[derive(Serialize)]
 struct User { 
@@ -44,7 +144,8 @@ struct User {
     spk_public: String,
     spk_signature: String,
     opk: String,
-    id: i32
+    id: i32,
+    log: KeyProof,
 }
 
 fn setup_logger() -> Result<(), fern::InitError> {
@@ -64,126 +165,264 @@ fn setup_logger() -> Result<(), fern::InitError> {
     Ok(())
 }
 
+async fn connect() -> Result<Db, Box<dyn std::error::Error>> {
+    // The migrations in `src/server/migrations` are compiled into the binary.
+    storage::connect(&std::env::var("DATABASE_URL")?).await
+}
+
+/// Applies pending migrations without starting the server.
+#[tokio::main]
+pub async fn migrate() -> Result<(), Box<dyn std::error::Error>> {
+    setup_logger().expect("Failed to initialize logger");
+    connect().await?;
+    Ok(())
+}
+
 #[tokio::main]
 pub async fn start() -> Result<(), Box<dyn std::error::Error>> {
     setup_logger().expect("Failed to initialize logger");
     
-    let db = Arc::new(PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&std::env::var("DATABASE_URL")?)
-        .await?);
+    let db = connect().await?;
+    let log = Arc::new(KeyLog::load(&transparency::key_path())?);
+    tokio::spawn(expire_requests(db.clone()));
 
     let app = Router::new()
+        .route("/auth/challenge/", post(auth::challenge))
+        .route("/auth/login/", post(auth::login))
         .route("/search/", post(search))
+        .route("/discoverable/", post(discoverable))
         .route("/create/", post(create))
         .route("/session/", post(session))
         .route("/create/session/", post(create_session))
         .route("/list/session/", post(get_session_list))
+        .route("/list/session/outgoing/", post(get_outgoing_list))
+        .route("/cancel/session/", post(cancel_session))
         .route("/get/session/", post(get_session))
+        .route("/decline/session/", post(decline_session))
+        .route("/block/", post(block))
+        .route("/unblock/", post(unblock))
+        .route("/list/block/", post(get_block_list))
         .route("/create/message/", post(create_message))
         .route("/message/", post(get_message))
-        .layer(Extension(db.clone()));
+        .route("/key/change/", post(get_key_change))
+        .route("/ack/", post(ack))
+        .route("/log/head/", post(transparency::head))
+        .route("/log/consistency/", post(transparency::consistency))
+        .route("/ws/", get(push::connect))
+        .layer(DefaultBodyLimit::max(MAX_BODY_BYTES))
+        .layer(middleware::from_fn(ratelimit::limit))
+        .layer(Extension(db.clone()))
+        .layer(Extension(log))
+        .layer(Extension(Arc::new(AuthState::default())))
+        .layer(Extension(Arc::new(PushHub::default())))
+        .layer(Extension(Arc::new(RateLimiter::from_env())));
 
     let listener = tokio::net::TcpListener::bind(std::env::var("SERVER_URL")?).await.unwrap();
     info!("Server is running on {}", std::env::var("SERVER_URL")?);
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
     
     Ok(())
 }
 
+/// Deletes session requests older than `REQUEST_EXPIRY_SECS`, once on startup and then every
+/// `REQUEST_PURGE_INTERVAL_SECS`.
+async fn expire_requests(db: Db) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(REQUEST_PURGE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        match db.expire_requests(Local::now().timestamp() - REQUEST_EXPIRY_SECS).await {
+            Ok(0) => {},
+            Ok(count) => info!("[Session] Expired {} stale session requests", count),
+            Err(e) => warn!("[Session] Failed to expire session requests: {}", e),
+        }
+    }
+}
+
 #[axum::debug_handler]
 async fn create(
-    Extension(db): Extension<Arc<PgPool>>, 
+    Extension(db): Extension<Db>, 
+    Extension(auth): Extension<Arc<AuthState>>,
     Json(payload): Json<CreatePayload>
-) -> impl IntoResponse {
-    let result = sqlx::query!("SELECT * FROM \"user\" WHERE account = $1", &payload.account)
-        .fetch_optional(db.as_ref())
-        .await.unwrap();
-    
-    if result.is_some() {
-        sqlx::query!("UPDATE \"user\" SET ik_public = $1, spk_public = $2, spk_signature = $3 WHERE account = $4", 
-            &payload.ik_public, &payload.spk_public, &payload.spk_signature, &payload.account
-        ).execute(db.as_ref()).await.unwrap();
-        sqlx::query!("DELETE FROM opk WHERE account = $1", &payload.account).execute(db.as_ref()).await.unwrap();
-        for key in payload.opk.iter() {
-            sqlx::query!(
-                "INSERT INTO opk (account, opk, id) VALUES ($1, $2, $3)",
-                &payload.account, key.key, key.id
-            ).execute(db.as_ref()).await.unwrap();
+) -> Result<StatusCode, ServerError> {
+    validate_account(&payload.account)?;
+    validate_key("ik_public", &payload.ik_public, 32)?;
+    validate_key("spk_public", &payload.spk_public, 32)?;
+    validate_key("spk_signature", &payload.spk_signature, 64)?;
+    if let Some(recovery) = &payload.recovery {
+        validate_key("recovery", recovery, 32)?;
+    }
+    if payload.opk.len() > MAX_OPKS {
+        return Err(ServerError::InvalidRequest(format!("At most {} one-time prekeys can be uploaded", MAX_OPKS)));
+    }
+    for key in payload.opk.iter() {
+        validate_key("opk", &key.key, 32)?;
+    }
+    
+    let mut user = UserRecord {
+        account: payload.account.clone(),
+        // Stored once in lowercase, the form the transparency log hashes and clients compare.
+        ik_public: payload.ik_public.to_ascii_lowercase(),
+        spk_public: payload.spk_public.clone(),
+        spk_signature: payload.spk_signature.clone(),
+        recovery: payload.recovery.clone(),
+        discoverable: payload.discoverable.unwrap_or(false),
+    };
+    let opks: Vec<OpkRecord> = payload.opk.iter()
+        .map(|key| OpkRecord { id: key.id, opk: key.key.clone() })
+        .collect();
+    
+    if let Some(row) = db.find_user(&payload.account).await? {
+        let proof = payload.proof.as_deref()
+            .and_then(|proof| hex::decode(proof).ok())
+            .zip(hex::decode(row.ik_public.trim()).ok().and_then(|key| <[u8; 32]>::try_from(key).ok()))
+            .is_some_and(|(proof, key)| xeddsa_verify(&key, &update_message(&payload), &proof))
+            && payload.challenge.as_deref().is_some_and(|challenge| auth.take_challenge(&payload.account, challenge));
+        let recovery = payload.recovery_code.as_deref()
+            .zip(row.recovery.as_deref())
+            .is_some_and(|(code, hash)| hex::encode(Sha256::digest(code.as_bytes())) == hash.trim());
+        
+        if !proof && !recovery {
+            warn!("[Signup] <{}> tried to replace the keys of an existing account without proof", payload.account);
+            return Err(ServerError::KeyProofRequired);
         }
-        info!("[Signup] <{}> already exists, updated the account", payload.account);
-    } else {
-        sqlx::query!(
-            "INSERT INTO \"user\" (account, ik_public, spk_public, spk_signature) VALUES ($1, $2, $3, $4)",
-            &payload.account, &payload.ik_public, &payload.spk_public, &payload.spk_signature
-        ).execute(db.as_ref()).await.unwrap();
-        for key in payload.opk.iter() {
-            sqlx::query!(
-                "INSERT INTO opk (account, opk, id) VALUES ($1, $2, $3)",
-                &payload.account, key.key, key.id
-            ).execute(db.as_ref()).await.unwrap();
+        
+        user.discoverable = payload.discoverable.unwrap_or(row.discoverable);
+        db.update_user(&user, &opks, Local::now().timestamp()).await?;
+        if row.ik_public.trim() != user.ik_public {
+            info!("[Signup] <{}> changed identity key, notified contacts", payload.account);
         }
+        info!("[Signup] <{}> already exists, updated the account with {}", 
+            payload.account, if proof { "a signed proof" } else { "a recovery code" });
+    } else {
+        validate_new_account(&payload.account)?;
+        db.create_user(&user, &opks).await?;
         info!("[Signup] <{}> created an account", payload.account);
     }
     
-    StatusCode::OK
+    Ok(StatusCode::OK)
+}
+
+/// The message an existing identity key signs to authorize replacing the registered keys. It
+/// covers everything the update stores and a challenge that is only valid once and for a minute,
+/// so a captured proof can neither be replayed nor attached to other prekeys or recovery hash.
+fn update_message(payload: &CreatePayload) -> Vec<u8> {
+    let opks = payload.opk.iter().fold(Sha256::new(), |digest, key| digest.chain_update(format!("{}:{};", key.id, key.key)));
+    format!("{}:{}:{}:{}:{}:{}:{}:{}",
+        UPDATE_CONTEXT,
+        payload.account,
+        payload.challenge.as_deref().unwrap_or_default(),
+        payload.ik_public,
+        payload.spk_public,
+        payload.spk_signature,
+        payload.recovery.as_deref().unwrap_or_default(),
+        hex::encode(opks.finalize())
+    ).into_bytes()
+}
+
+#[derive(Serialize)]
+struct KeyChangePayload {
+    target: String,
+    ik_public: String,
+    timestamp: i64,
 }
 
+#[axum::debug_handler]
+async fn get_key_change(
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+) -> Result<Json<Vec<KeyChangePayload>>, ServerError> {
+    let mut changes: Vec<KeyChangePayload> = db.take_key_changes(&account).await?.into_iter()
+        .map(|row| KeyChangePayload { target: row.target, ik_public: row.ik_public, timestamp: row.timestamp })
+        .collect();
+    changes.sort_by_key(|change| change.timestamp);
+    
+    info!("[Key] <{}> received {} key changes", account, changes.len());
+    Ok(Json(changes))
+}
 
 #[axum::debug_handler]
 async fn search(
-    Extension(db): Extension<Arc<PgPool>>, 
+    Extension(db): Extension<Db>, 
+    AuthUser(account): AuthUser,
     Json(payload): Json<SearchPayload>
-) -> impl IntoResponse {
-    info!("[Search] {} is searching for {}", payload.account, payload.target);
-    let result = sqlx::query!("SELECT account FROM \"user\" WHERE account like $1 and account != $2", format!("%{}%", payload.target), payload.account)
-        .fetch_all(db.as_ref())
-        .await.unwrap();
-    
-    let mut users = vec![];
-    for row in result {
-        users.push(row.account);
+) -> Result<Json<Page<String, String>>, ServerError> {
+    validate_account(&payload.target)?;
+    
+    if !payload.prefix {
+        // An exact lookup only tells whether a name the caller already knows exists.
+        info!("[Search] <{}> looked up {}", account, payload.target);
+        let found = db.find_user(&payload.target).await?.filter(|user| user.account != account);
+        return Ok(Json(Page { items: found.into_iter().map(|user| user.account).collect(), next: None }));
+    }
+    
+    if payload.target.len() < MIN_PREFIX_LENGTH {
+        return Err(ServerError::InvalidRequest(format!("Prefix searches need at least {} characters", MIN_PREFIX_LENGTH)));
+    }
+    info!("[Search] <{}> is searching for discoverable accounts starting with {}", account, payload.target);
+    let limit = payload.page.limit().min(MAX_SEARCH_RESULTS);
+    let users = db.search_users(&payload.target, &account, payload.page.after.as_deref().unwrap_or(""), limit).await?;
+    Ok(Json(Page::new(users, limit, |user| user.clone())))
+}
+
+/// Returns whether the caller can be found by prefix search, after changing it if asked to.
+#[axum::debug_handler]
+async fn discoverable(
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<DiscoverablePayload>
+) -> Result<Json<DiscoverableResponse>, ServerError> {
+    if let Some(discoverable) = payload.discoverable {
+        db.set_discoverable(&account, discoverable).await?;
+        info!("[Search] <{}> is {} discoverable", account, if discoverable { "now" } else { "no longer" });
     }
-    Json(users)
+    
+    let user = db.find_user(&account).await?.ok_or_else(|| ServerError::UserNotFound(account.clone()))?;
+    Ok(Json(DiscoverableResponse { discoverable: user.discoverable }))
 }
 
 #[axum::debug_handler]
 async fn session(
-    Extension(db): Extension<Arc<PgPool>>,
+    Extension(db): Extension<Db>,
+    Extension(log): Extension<Arc<KeyLog>>,
+    AuthUser(account): AuthUser,
     Json(payload): Json<NormalPayload>
-) -> impl IntoResponse {
-    info!("[Session] Creating session for {}", payload.target);
-    let result = sqlx::query!("SELECT * FROM \"user\" WHERE account = $1", &payload.target)
-        .fetch_optional(db.as_ref())
-        .await.unwrap();
-    
-    if let Some(row) = result {
-        let result = sqlx::query!("SELECT * FROM opk WHERE account = $1 ORDER BY RANDOM() LIMIT 1;", &payload.target)
-            .fetch_optional(db.as_ref())
-            .await.unwrap();
-        
-        if let Some(opk) = result {
-            let user = User {
-                account: row.account,
-                ik_public: row.ik_public,
-                spk_public: row.spk_public,
-                spk_signature: row.spk_signature,
-                opk: opk.opk,
-                id: opk.id
-            };
-            
-            sqlx::query!("DELETE FROM opk WHERE opk = $1", user.opk).execute(db.as_ref()).await.unwrap();
-            
-            info!("[Session] <{}> created a session", payload.target);
-            (StatusCode::OK, Json(Some(user)))
-        } else {
+) -> Result<Json<User>, ServerError> {
+    validate_account(&payload.target)?;
+    
+    info!("[Session] <{}> is creating a session with {}", account, payload.target);
+    if db.is_blocked(&payload.target, &account).await? {
+        // Answer as if the account did not exist so blocked users cannot drain its prekeys.
+        warn!("[Session] <{}> is blocked by <{}>", account, payload.target);
+        return Err(ServerError::UserNotFound(payload.target));
+    }
+    let row = db.find_user(&payload.target).await?
+        .ok_or_else(|| {
+            warn!("[Session] <{}> does not exist", payload.target);
+            ServerError::UserNotFound(payload.target.clone())
+        })?;
+    
+    let entry = db.latest_key(&payload.target).await?
+        .ok_or_else(|| ServerError::UserNotFound(payload.target.clone()))?;
+    let proof = log.prove(&db, &entry).await?;
+    
+    let opk = db.claim_opk(&payload.target, &account, Local::now().timestamp()).await?
+        .ok_or_else(|| {
             warn!("[Session] <{}> does not have any one-time prekeys", payload.target);
-            (StatusCode::NOT_FOUND, Json(None::<User>))
-        }
-    } else {
-        warn!("[Session] <{}> does not exist", payload.target);
-        (StatusCode::NOT_FOUND, Json(None::<User>))
-    } 
+            ServerError::NoPrekeys(payload.target.clone())
+        })?;
+    
+    let user = User {
+        account: row.account,
+        ik_public: row.ik_public,
+        spk_public: row.spk_public,
+        spk_signature: row.spk_signature,
+        opk: opk.opk,
+        id: opk.id,
+        log: proof,
+    };
+    
+    info!("[Session] <{}> claimed one-time prekey {} of <{}>", account, user.id, payload.target);
+    Ok(Json(user))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -193,75 +432,227 @@ struct RequestPayload {
     ikp: String,
     ekp: String,
     opk_id: i32,
+    timestamp: i64,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewRequestPayload {
+    target: String,
+    ikp: String,
+    ekp: String,
+    opk_id: i32,
+    /// The first message, encrypted under the session the request sets up, so the target can
+    /// read it as soon as it accepts.
+    #[serde(default)]
+    message: Option<String>,
 }
 
 #[axum::debug_handler]
 async fn create_session(
-    Extension(db): Extension<Arc<PgPool>>,
-    Json(payload): Json<RequestPayload>
-) -> impl IntoResponse {
-    info!("[Session] {} Creating session for {}", payload.account, payload.target);
-    
-    let temp = sqlx::query!(
-        "INSERT INTO request (account, target, ek, ikp, id) VALUES ($1, $2, $3, $4, $5)",
-        &payload.account, &payload.target, &payload.ekp, &payload.ikp, payload.opk_id
-    ).execute(db.as_ref()).await;
-    
-    if temp.is_ok() {
-        info!("[Session] <{}> requested a session with <{}>", payload.account, payload.target);
-        StatusCode::OK
-    } else {
-        warn!("[Session] <{}> failed to request a session with <{}>", payload.account, payload.target);
-        StatusCode::INTERNAL_SERVER_ERROR
+    Extension(db): Extension<Db>,
+    Extension(hub): Extension<Arc<PushHub>>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<NewRequestPayload>
+) -> Result<StatusCode, ServerError> {
+    validate_account(&payload.target)?;
+    validate_key("ikp", &payload.ikp, 32)?;
+    validate_key("ekp", &payload.ekp, 32)?;
+    if payload.message.as_ref().is_some_and(|message| message.len() > MAX_MESSAGE_BYTES) {
+        warn!("[Session] <{}> sent an oversized first message to <{}>", account, payload.target);
+        return Err(ServerError::MessageTooLarge(MAX_MESSAGE_BYTES));
+    }
+    
+    info!("[Session] {} Creating session for {}", account, payload.target);
+    
+    if db.count_requests(&payload.target).await? >= MAX_PENDING_REQUESTS {
+        warn!("[Session] <{}> has too many pending requests", payload.target);
+        return Err(ServerError::QueueFull(payload.target));
     }
+    if db.is_blocked(&payload.target, &account).await? {
+        warn!("[Session] Dropped the request of <{}>, who is blocked by <{}>", account, payload.target);
+        return Ok(StatusCode::OK);
+    }
+    
+    let request = RequestRecord {
+        account: account.clone(),
+        target: payload.target.clone(),
+        ek: payload.ekp,
+        ikp: payload.ikp,
+        id: payload.opk_id,
+        timestamp: Local::now().timestamp(),
+        message: payload.message,
+    };
+    if !db.create_request(&request).await? {
+        warn!("[Session] <{}> already requested a session with <{}>", account, payload.target);
+        return Err(ServerError::RequestExists(payload.target));
+    }
+    
+    hub.notify(&payload.target, PushEvent::Request { account: account.clone(), timestamp: request.timestamp });
+    info!("[Session] <{}> requested a session with <{}>", account, payload.target);
+    Ok(StatusCode::OK)
 }
 
 #[axum::debug_handler]
 async fn get_session_list(
-    Extension(db): Extension<Arc<PgPool>>,
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<PagePayload<String>>
+) -> Result<Json<Page<PendingRequestRecord, String>>, ServerError> {
+    let limit = payload.limit();
+    let requests = db.list_requests(&account, Local::now().timestamp_millis(), payload.after.as_deref().unwrap_or(""), limit).await?;
+    
+    info!("[Session] <{}> received {} requests", account, requests.len());
+    Ok(Json(Page::new(requests, limit, |request| request.account.clone())))
+}
+
+/// The requests the caller made that the target has not accepted, declined or let expire yet.
+#[axum::debug_handler]
+async fn get_outgoing_list(
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<PagePayload<String>>
+) -> Result<Json<Page<PendingRequestRecord, String>>, ServerError> {
+    let limit = payload.limit();
+    let requests = db.list_outgoing_requests(&account, payload.after.as_deref().unwrap_or(""), limit).await?;
+    
+    info!("[Session] <{}> has {} outgoing requests", account, requests.len());
+    Ok(Json(Page::new(requests, limit, |request| request.account.clone())))
+}
+
+/// Withdraws the pending session request of the caller to `target`.
+#[axum::debug_handler]
+async fn cancel_session(
+    Extension(db): Extension<Db>,
+    Extension(hub): Extension<Arc<PushHub>>,
+    AuthUser(account): AuthUser,
     Json(payload): Json<NormalPayload>
-) -> impl IntoResponse {
-    let result = sqlx::query!("SELECT account FROM request WHERE target = $1",&payload.target)
-        .fetch_all(db.as_ref())
-        .await.unwrap();
-    
-    let mut users = vec![];
-    for row in result {
-        users.push(row.account);
+) -> Result<StatusCode, ServerError> {
+    validate_account(&payload.target)?;
+    
+    if db.find_request(&account, &payload.target).await?.is_none() {
+        warn!("[Session] <{}> tried to cancel a missing request to <{}>", account, payload.target);
+        return Err(ServerError::RequestNotFound(payload.target));
     }
+    db.delete_requests(&payload.target, std::slice::from_ref(&account)).await?;
     
-    info!("[Session] <{}> received {} requests", payload.target, users.len());
-    Json(users)
+    hub.notify(&payload.target, PushEvent::Cancelled { account: account.clone() });
+    info!("[Session] <{}> cancelled the request to <{}>", account, payload.target);
+    Ok(StatusCode::OK)
 }
 
 #[axum::debug_handler]
 async fn get_session(
-    Extension(db): Extension<Arc<PgPool>>,
-    Json(payload): Json<SearchPayload>
-) -> impl IntoResponse {
-    let result = sqlx::query!("SELECT * FROM request WHERE target = $1 and account = $2",&payload.target, &payload.account)
-        .fetch_optional(db.as_ref())
-        .await.unwrap();
-    
-    if let Some(row) = result {
-        info!("[Session] <{}> accepted a session with <{}>", payload.account, payload.target);
-        sqlx::query!("DELETE FROM request WHERE account = $1 and target = $2", &payload.account, &payload.target).execute(db.as_ref()).await.unwrap();
-        (StatusCode::OK, Json(Some(RequestPayload {
-            account: row.account,
-            target: row.target,
-            ikp: row.ikp,
-            ekp: row.ek,
-            opk_id: row.id
-        })))
-    } else {
-        warn!("[Session] <{}> failed to accept a session with <{}>", payload.account, payload.target);
-        (StatusCode::NOT_FOUND, Json(None::<RequestPayload>))
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<NormalPayload>
+) -> Result<Json<RequestPayload>, ServerError> {
+    validate_account(&payload.target)?;
+    
+    let row = db.find_request(&payload.target, &account).await?
+        .ok_or_else(|| {
+            warn!("[Session] <{}> failed to accept a session with <{}>", account, payload.target);
+            ServerError::RequestNotFound(payload.target.clone())
+        })?;
+    
+    info!("[Session] <{}> accepted a session with <{}>", account, payload.target);
+    db.lease_request(&payload.target, &account, Local::now().timestamp_millis() + LEASE_MILLIS).await?;
+    
+    Ok(Json(RequestPayload {
+        account: row.account,
+        target: row.target,
+        ikp: row.ikp,
+        ekp: row.ek,
+        opk_id: row.id,
+        timestamp: row.timestamp,
+        message: row.message,
+    }))
+}
+
+#[derive(Deserialize)]
+struct DeclinePayload {
+    target: String,
+    /// Whether to tell the requester over the push connection.
+    #[serde(default)]
+    notify: bool,
+}
+
+/// Deletes the pending session request from `target` without accepting it.
+#[axum::debug_handler]
+async fn decline_session(
+    Extension(db): Extension<Db>,
+    Extension(hub): Extension<Arc<PushHub>>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<DeclinePayload>
+) -> Result<StatusCode, ServerError> {
+    validate_account(&payload.target)?;
+    
+    if db.find_request(&payload.target, &account).await?.is_none() {
+        warn!("[Session] <{}> tried to decline a missing request from <{}>", account, payload.target);
+        return Err(ServerError::RequestNotFound(payload.target));
     }
+    db.delete_requests(&account, std::slice::from_ref(&payload.target)).await?;
+    
+    if payload.notify {
+        hub.notify(&payload.target, PushEvent::Declined { account: account.clone() });
+    }
+    info!("[Session] <{}> declined the request of <{}>", account, payload.target);
+    Ok(StatusCode::OK)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MessagePayload {
-    account: String,
+/// Stops `target` from claiming prekeys of, requesting sessions with or messaging the caller,
+/// and drops whatever it has queued for the caller.
+#[axum::debug_handler]
+async fn block(
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<NormalPayload>
+) -> Result<StatusCode, ServerError> {
+    validate_account(&payload.target)?;
+    if payload.target == account {
+        return Err(ServerError::InvalidRequest("Accounts cannot block themselves".to_string()));
+    }
+    
+    db.block_user(&account, &payload.target, Local::now().timestamp()).await?;
+    info!("[Block] <{}> blocked <{}>", account, payload.target);
+    Ok(StatusCode::OK)
+}
+
+#[axum::debug_handler]
+async fn unblock(
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<NormalPayload>
+) -> Result<StatusCode, ServerError> {
+    validate_account(&payload.target)?;
+    
+    db.unblock_user(&account, &payload.target).await?;
+    info!("[Block] <{}> unblocked <{}>", account, payload.target);
+    Ok(StatusCode::OK)
+}
+
+#[axum::debug_handler]
+async fn get_block_list(
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<PagePayload<String>>
+) -> Result<Json<Page<String, String>>, ServerError> {
+    let limit = payload.limit();
+    let users = db.list_blocked(&account, payload.after.as_deref().unwrap_or(""), limit).await?;
+    
+    info!("[Block] <{}> received {} blocked accounts", account, users.len());
+    Ok(Json(Page::new(users, limit, |user| user.clone())))
+}
+
+#[derive(Deserialize)]
+struct MessageQueryPayload {
+    target: String,
+    #[serde(flatten)]
+    page: PagePayload<i32>,
+}
+
+#[derive(Deserialize)]
+struct NewMessagePayload {
     target: String,
     message: String,
     timestamp: i64,
@@ -269,60 +660,94 @@ pub struct MessagePayload {
 
 #[axum::debug_handler]
 async fn create_message(
-    Extension(db): Extension<Arc<PgPool>>,
-    Json(payload): Json<MessagePayload>
-) -> impl IntoResponse {
-    info!("[Message] {} sent a message to {}", payload.account, payload.target);
-    let temp = sqlx::query!(
-        "INSERT INTO chat (account, target, message, timestamp) VALUES ($1, $2, $3, $4)",
-        &payload.account, &payload.target, &payload.message, &payload.timestamp
-    ).execute(db.as_ref()).await;
-    
-    if temp.is_ok() {
-        info!("[Message] <{}> sent a message to <{}>", payload.account, payload.target);
-        StatusCode::OK
-    } else {
-        warn!("[Message] <{}> failed to send a message to <{}>", payload.account, payload.target);
-        StatusCode::INTERNAL_SERVER_ERROR
+    Extension(db): Extension<Db>,
+    Extension(hub): Extension<Arc<PushHub>>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<NewMessagePayload>
+) -> Result<StatusCode, ServerError> {
+    validate_account(&payload.target)?;
+    validate_timestamp(payload.timestamp)?;
+    if payload.message.len() > MAX_MESSAGE_BYTES {
+        warn!("[Message] <{}> sent an oversized message to <{}>", account, payload.target);
+        return Err(ServerError::MessageTooLarge(MAX_MESSAGE_BYTES));
+    }
+    
+    info!("[Message] {} sent a message to {}", account, payload.target);
+    if db.is_blocked(&payload.target, &account).await? {
+        // The sender is not told, so blocking does not show up as an error on their side. This
+        // comes before the quota, which would otherwise tell a full queue from a block.
+        warn!("[Message] Dropped a message from <{}>, who is blocked by <{}>", account, payload.target);
+        return Ok(StatusCode::OK);
     }
+    let (total, sender) = db.count_messages(&payload.target, &account).await?;
+    if total >= MAX_QUEUED_MESSAGES || sender >= MAX_QUEUED_PER_SENDER {
+        warn!("[Message] The queue of <{}> is full for <{}>", payload.target, account);
+        return Err(ServerError::QueueFull(payload.target));
+    }
+    
+    let record = db.insert_message(&account, &payload.target, &payload.message, payload.timestamp, Local::now().timestamp()).await?;
+    
+    hub.notify(&payload.target, PushEvent::Message(record));
+    info!("[Message] <{}> sent a message to <{}>", account, payload.target);
+    Ok(StatusCode::OK)
 }
 
 #[axum::debug_handler]
 async fn get_message(
-    Extension(db): Extension<Arc<PgPool>>,
-    Json(payload): Json<SearchPayload>
-) -> impl IntoResponse {
-    info!("[Message] {} sent a message to {}", payload.account, payload.target);
-    let temp = sqlx::query!(
-        "SELECT * FROM CHAT WHERE account = $1 and target = $2 order by timestamp",
-        &payload.target, &payload.account
-    ).fetch_all(db.as_ref()).await;
-    
-    match temp {
-        Ok(rows) => {
-            let result: Vec<MessagePayload> = rows.iter()
-                .map(|row| MessagePayload {
-                    account: row.account.clone(),
-                    target: row.target.clone(),
-                    message: row.message.clone(),
-                    timestamp: row.timestamp,
-                })
-                .collect();
-
-            info!("[Message] Found {} messages between {} and {}", 
-                result.len(), payload.account, payload.target);
-            
-            sqlx::query!(
-                "DELETE FROM chat WHERE account = $1 and target = $2",
-                &payload.target, &payload.account
-            ).execute(db.as_ref()).await.unwrap();
-                
-            (StatusCode::OK, Json(result))
-        },
-        Err(e) => {
-            warn!("[Message] Error fetching messages: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new()))
-        }
-    }
+    Extension(db): Extension<Db>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<MessageQueryPayload>
+) -> Result<Json<Page<MessageRecord, i32>>, ServerError> {
+    validate_account(&payload.target)?;
+    
+    info!("[Message] {} is fetching messages from {}", account, payload.target);
+    let now = Local::now().timestamp_millis();
+    let limit = payload.page.limit();
+    let result = db.lease_messages(&payload.target, &account, now, now + LEASE_MILLIS, payload.page.after.unwrap_or(0), limit).await?;
 
+    info!("[Message] Leased {} messages between {} and {}", 
+        result.len(), account, payload.target);
+    
+    Ok(Json(Page::new(result, limit, |message| message.id)))
 }
+
+#[derive(Deserialize)]
+struct AckPayload {
+    #[serde(default)]
+    messages: Vec<i32>,
+    #[serde(default)]
+    requests: Vec<String>,
+}
+
+/// Removes the messages and session requests the caller has processed. Anything fetched but
+/// never acknowledged becomes deliverable again once its lease runs out.
+#[axum::debug_handler]
+async fn ack(
+    Extension(db): Extension<Db>,
+    Extension(hub): Extension<Arc<PushHub>>,
+    AuthUser(account): AuthUser,
+    Json(payload): Json<AckPayload>
+) -> Result<StatusCode, ServerError> {
+    if payload.messages.len() + payload.requests.len() > MAX_PAGE_SIZE as usize {
+        return Err(ServerError::InvalidRequest(format!("At most {} items can be acknowledged at once", MAX_PAGE_SIZE)));
+    }
+    for request in payload.requests.iter() {
+        validate_account(request)?;
+    }
+    
+    let acked = db.delete_messages(&account, &payload.messages).await?;
+    
+    let mut senders: HashMap<String, usize> = HashMap::new();
+    for sender in acked {
+        *senders.entry(sender).or_default() += 1;
+    }
+    for (sender, count) in senders.iter() {
+        hub.notify(sender, PushEvent::Receipt { account: account.clone(), count: *count });
+    }
+    
+    db.delete_requests(&account, &payload.requests).await?;
+    
+    info!("[Ack] <{}> acknowledged {} messages and {} requests", 
+        account, senders.values().sum::<usize>(), payload.requests.len());
+    Ok(StatusCode::OK)
+}
\ No newline at end of file
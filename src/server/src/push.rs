@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::{
+    Extension,
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::IntoResponse,
+};
+use chrono::Local;
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use crate::{LEASE_MILLIS, MAX_PAGE_SIZE};
+use crate::auth::AuthUser;
+use crate::storage::{Db, MessageRecord, Storage};
+
+/// What the server pushes to a connected client. Messages carry the ciphertext itself, requests
+/// and receipts only name the peer they are about.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PushEvent {
+    Message(MessageRecord),
+    Request { account: String, timestamp: i64 },
+    /// `account` withdrew its session request to the receiver.
+    Cancelled { account: String },
+    /// `account` declined the session request of the receiver.
+    Declined { account: String },
+    Receipt { account: String, count: usize },
+}
+
+/// The open push connections of every account. An account may be connected more than once.
+#[derive(Default)]
+pub struct PushHub {
+    clients: Mutex<HashMap<String, Vec<UnboundedSender<PushEvent>>>>,
+}
+
+impl PushHub {
+    fn subscribe(&self, account: &str) -> UnboundedReceiver<PushEvent> {
+        let (sender, receiver) = unbounded_channel();
+        self.clients.lock().unwrap().entry(account.to_string()).or_default().push(sender);
+        receiver
+    }
+
+    /// Hands `event` to every connection of `account` and returns whether any was open.
+    pub fn notify(&self, account: &str, event: PushEvent) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let Some(senders) = clients.get_mut(account) else { return false; };
+
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+        if senders.is_empty() {
+            clients.remove(account);
+            return false;
+        }
+        true
+    }
+}
+
+#[axum::debug_handler]
+pub async fn connect(
+    Extension(db): Extension<Db>,
+    Extension(hub): Extension<Arc<PushHub>>,
+    AuthUser(account): AuthUser,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| serve(socket, account, hub, db))
+}
+
+async fn serve(socket: WebSocket, account: String, hub: Arc<PushHub>, db: Db) {
+    info!("[Push] <{}> connected", account);
+    let mut events = hub.subscribe(&account);
+    let (mut sender, mut receiver) = socket.split();
+    // The first tick fires at once and flushes what was queued while the client was offline,
+    // later ticks redeliver messages whose lease ran out without an ack.
+    let mut redeliver = tokio::time::interval(Duration::from_millis(LEASE_MILLIS as u64));
+
+    'serve: loop {
+        tokio::select! {
+            _ = redeliver.tick() => {
+                let mut after = 0;
+                loop {
+                    let queued = match db.pending_messages(&account, Local::now().timestamp_millis(), after, MAX_PAGE_SIZE).await {
+                        Ok(queued) => queued,
+                        Err(e) => {
+                            warn!("[Push] Failed to load the queue of <{}>: {}", account, e);
+                            break;
+                        }
+                    };
+                    let full = queued.len() as i64 == MAX_PAGE_SIZE;
+                    
+                    for row in queued {
+                        after = row.id;
+                        if !deliver(&mut sender, PushEvent::Message(row), db.as_ref()).await { break 'serve; }
+                    }
+                    if !full { break; }
+                }
+            },
+            event = events.recv() => match event {
+                Some(event) => if !deliver(&mut sender, event, db.as_ref()).await { break; },
+                None => break,
+            },
+            message = receiver.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {},
+            },
+        }
+    }
+
+    info!("[Push] <{}> disconnected", account);
+}
+
+/// Writes one event to the socket. A message is leased before it is written, so it is pushed
+/// once per lease even when it is announced twice or fetched over HTTP at the same time. It
+/// stays queued until the client acknowledges it.
+async fn deliver(
+    sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
+    event: PushEvent,
+    db: &dyn Storage,
+) -> bool {
+    if let PushEvent::Message(MessageRecord { id, .. }) = &event {
+        let now = Local::now().timestamp_millis();
+        match db.lease_message(*id, now, now + LEASE_MILLIS).await {
+            Ok(true) => {},
+            Ok(false) => return true,
+            // Left for the next redelivery round.
+            Err(e) => {
+                warn!("[Push] Failed to lease message {}: {}", id, e);
+                return true;
+            }
+        }
+    }
+
+    if let Err(e) = sender.send(Message::Text(serde_json::to_string(&event).unwrap())).await {
+        warn!("[Push] Failed to push an event: {}", e);
+        return false;
+    }
+    true
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Request},
+    http::{HeaderName, header::{AUTHORIZATION, RETRY_AFTER}},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+use serde::Deserialize;
+use crate::auth::AuthState;
+use crate::error::ServerError;
+use crate::MAX_BODY_BYTES;
+
+/// Default limits as (path, variable, requests, seconds). Each can be overridden with an
+/// environment variable such as `RATE_LIMIT_SESSION=5/60`. `/session/` hands out a one-time
+/// prekey of someone else on every call, so it is the strictest.
+const LIMITS: &[(&str, &str, u32, u64)] = &[
+    ("/session/", "RATE_LIMIT_SESSION", 5, 60),
+    ("/create/", "RATE_LIMIT_CREATE", 5, 60),
+    ("/create/session/", "RATE_LIMIT_REQUEST", 10, 60),
+    ("/create/message/", "RATE_LIMIT_MESSAGE", 60, 60),
+    ("/search/", "RATE_LIMIT_SEARCH", 30, 60),
+    ("/auth/challenge/", "RATE_LIMIT_AUTH", 20, 60),
+    ("/auth/login/", "RATE_LIMIT_AUTH", 20, 60),
+];
+const DEFAULT_LIMIT: (&str, u32, u64) = ("RATE_LIMIT_DEFAULT", 120, 60);
+/// Bundles of one account handed out to everyone together, so many accounts or addresses cannot
+/// drain its one-time prekeys between two of its uploads.
+const TARGET_LIMIT: (&str, &str, u32, u64) = ("/session/", "RATE_LIMIT_SESSION_TARGET", 10, 60);
+/// Names the header a reverse proxy puts the client address in, such as `X-Forwarded-For`.
+/// Without it every client behind the proxy shares the proxy's address.
+const PROXY_HEADER: &str = "TRUSTED_PROXY_HEADER";
+/// An address gets this many times the limit of an account, since several accounts may share it.
+const IP_FACTOR: f64 = 5.0;
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Clone, Copy)]
+struct Limit {
+    capacity: f64,
+    per_second: f64,
+}
+
+impl Limit {
+    fn from_env(variable: &str, requests: u32, seconds: u64) -> Self {
+        let (requests, seconds) = std::env::var(variable).ok()
+            .and_then(|value| {
+                let (requests, seconds) = value.split_once('/')?;
+                Some((requests.trim().parse::<u32>().ok()?, seconds.trim().parse::<u64>().ok()?))
+            })
+            .filter(|(requests, seconds)| *requests > 0 && *seconds > 0)
+            .unwrap_or((requests, seconds));
+
+        Self { capacity: requests as f64, per_second: requests as f64 / seconds as f64 }
+    }
+
+    fn scale(self, factor: f64) -> Self {
+        Self { capacity: self.capacity * factor, per_second: self.per_second * factor }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.capacity);
+        self.updated = now;
+    }
+}
+
+/// Token buckets per endpoint, one for every account and one for every remote address, and for
+/// bundle requests one for every target.
+pub struct RateLimiter {
+    limits: HashMap<&'static str, Limit>,
+    default: Limit,
+    target: Limit,
+    proxy_header: Option<HeaderName>,
+    buckets: Mutex<HashMap<(String, String), (Bucket, Limit)>>,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> Self {
+        Self {
+            limits: LIMITS.iter()
+                .map(|(path, variable, requests, seconds)| (*path, Limit::from_env(variable, *requests, *seconds)))
+                .collect(),
+            default: Limit::from_env(DEFAULT_LIMIT.0, DEFAULT_LIMIT.1, DEFAULT_LIMIT.2),
+            target: Limit::from_env(TARGET_LIMIT.1, TARGET_LIMIT.2, TARGET_LIMIT.3),
+            proxy_header: std::env::var(PROXY_HEADER).ok()
+                .and_then(|name| HeaderName::try_from(name.trim()).ok()),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one token from every bucket in `keys`, or none if any of them is empty. On
+    /// rejection returns the seconds until the request would be allowed.
+    fn acquire(&self, path: &str, keys: &[(String, Limit)]) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, (bucket, limit)| {
+                bucket.refill(*limit, now);
+                bucket.tokens < limit.capacity
+            });
+        }
+
+        let mut wait = 0.0f64;
+        for (key, limit) in keys {
+            let (bucket, _) = buckets.entry((path.to_string(), key.clone()))
+                .or_insert((Bucket { tokens: limit.capacity, updated: now }, *limit));
+            bucket.refill(*limit, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max((1.0 - bucket.tokens) / limit.per_second);
+            }
+        }
+
+        if wait > 0.0 {
+            return Err(wait.ceil() as u64);
+        }
+        for (key, _) in keys {
+            buckets.get_mut(&(path.to_string(), key.clone())).unwrap().0.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct TargetPayload { target: String }
+
+/// The address of the client, from the configured proxy header if there is one. A proxy appends
+/// the address it saw to the header, so the last entry is the one it vouches for.
+fn client_address(limiter: &RateLimiter, request: &Request) -> Option<String> {
+    match &limiter.proxy_header {
+        Some(header) => request.headers().get(header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .map(|address| address.trim().to_string())
+            .filter(|address| !address.is_empty()),
+        None => request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip().to_string()),
+    }
+}
+
+/// Rejects requests over the limit of their endpoint with `429 Too Many Requests`.
+pub async fn limit(request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    let limiter = request.extensions().get::<Arc<RateLimiter>>().cloned();
+    let auth = request.extensions().get::<Arc<AuthState>>().cloned();
+
+    let Some(limiter) = limiter else { return next.run(request).await; };
+    let limit = limiter.limits.get(path.as_str()).copied().unwrap_or(limiter.default);
+    let address = client_address(&limiter, &request);
+
+    let mut keys = vec![];
+    if let Some(address) = &address {
+        keys.push((format!("ip:{}", address), limit.scale(IP_FACTOR)));
+    }
+    let account = request.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .zip(auth)
+        .and_then(|(token, auth)| auth.account(token));
+    if let Some(account) = &account {
+        keys.push((format!("account:{}", account), limit));
+    }
+
+    // The target is in the body, which is read here and handed on to the handler unchanged.
+    let request = if path == TARGET_LIMIT.0 {
+        let (parts, body) = request.into_parts();
+        let bytes = match to_bytes(body, MAX_BODY_BYTES).await {
+            Ok(bytes) => bytes,
+            Err(_) => return ServerError::InvalidRequest("The request body could not be read".to_string()).into_response(),
+        };
+        if let Ok(payload) = serde_json::from_slice::<TargetPayload>(&bytes) {
+            keys.push((format!("target:{}", payload.target), limiter.target));
+        }
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        request
+    };
+
+    match limiter.acquire(&path, &keys) {
+        Ok(()) => next.run(request).await,
+        Err(retry) => {
+            warn!("[Limit] {} from <{}> ({:?}) was rate limited", path, account.unwrap_or_default(), address);
+            ([(RETRY_AFTER, retry.to_string())], ServerError::RateLimited(retry)).into_response()
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use axum::async_trait;
+use super::{KeyChangeRecord, KeyLogRecord, MessageRecord, OpkRecord, PendingRequestRecord, RequestRecord, Result, Storage, UserRecord};
+
+#[derive(Default)]
+struct State {
+    users: BTreeMap<String, UserRecord>,
+    opks: HashMap<String, Vec<OpkRecord>>,
+    /// Keyed by (account, target), with the lease of each request.
+    requests: BTreeMap<(String, String), (RequestRecord, Option<i64>)>,
+    contacts: HashSet<(String, String)>,
+    /// Keyed by (account, blocked account), with the time of blocking.
+    blocked: BTreeMap<(String, String), i64>,
+    key_changes: Vec<(String, KeyChangeRecord)>,
+    key_log: Vec<KeyLogRecord>,
+    /// (account, opk id, claimant, timestamp) of every claimed one-time prekey.
+    opk_claims: Vec<(String, i32, String, i64)>,
+    /// Keyed by id, with the lease of each message.
+    chat: BTreeMap<i32, (MessageRecord, Option<i64>)>,
+    next_id: i32,
+    /// The last sequence number handed out from one account to another.
+    sequences: HashMap<(String, String), i64>,
+}
+
+impl State {
+    fn append_key_log(&mut self, account: &str, ik_public: &str) {
+        let version = self.key_log.iter().filter(|entry| entry.account == account).count() as i32 + 1;
+        self.key_log.push(KeyLogRecord {
+            position: self.key_log.len() as i64,
+            account: account.to_string(),
+            ik_public: ik_public.to_string(),
+            version,
+        });
+    }
+}
+
+fn available(lease: Option<i64>, now: i64) -> bool {
+    lease.is_none_or(|until| until < now)
+}
+
+/// Keeps everything in process memory and loses it on restart. Meant for development and
+/// trying the server out.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn find_user(&self, account: &str) -> Result<Option<UserRecord>> {
+        Ok(self.state.lock().unwrap().users.get(account).cloned())
+    }
+
+    async fn create_user(&self, user: &UserRecord, opks: &[OpkRecord]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.users.insert(user.account.clone(), user.clone());
+        state.opks.insert(user.account.clone(), opks.to_vec());
+        state.append_key_log(&user.account, &user.ik_public);
+        Ok(())
+    }
+
+    async fn update_user(&self, user: &UserRecord, opks: &[OpkRecord], timestamp: i64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let previous = state.users.insert(user.account.clone(), user.clone()).ok_or(sqlx::Error::RowNotFound)?;
+        state.opks.insert(user.account.clone(), opks.to_vec());
+
+        if previous.ik_public != user.ik_public {
+            state.append_key_log(&user.account, &user.ik_public);
+            let contacts: Vec<String> = state.contacts.iter()
+                .filter(|(account, _)| *account == user.account)
+                .map(|(_, target)| target.clone())
+                .collect();
+            for contact in contacts {
+                state.key_changes.push((contact, KeyChangeRecord {
+                    target: user.account.clone(),
+                    ik_public: user.ik_public.clone(),
+                    timestamp,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    async fn search_users(&self, prefix: &str, exclude: &str, after: &str, limit: i64) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.users.values()
+            .filter(|user| user.discoverable && user.account.starts_with(prefix))
+            .map(|user| &user.account)
+            .filter(|account| *account != exclude && account.as_str() > after)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn set_discoverable(&self, account: &str, discoverable: bool) -> Result<()> {
+        if let Some(user) = self.state.lock().unwrap().users.get_mut(account) {
+            user.discoverable = discoverable;
+        }
+        Ok(())
+    }
+
+    async fn claim_opk(&self, account: &str, claimant: &str, timestamp: i64) -> Result<Option<OpkRecord>> {
+        let mut state = self.state.lock().unwrap();
+        let Some(opks) = state.opks.get_mut(account) else { return Ok(None); };
+        let Some(index) = opks.iter().enumerate().min_by_key(|(_, opk)| opk.id).map(|(index, _)| index) else {
+            return Ok(None);
+        };
+
+        let opk = opks.remove(index);
+        state.opk_claims.push((account.to_string(), opk.id, claimant.to_string(), timestamp));
+        Ok(Some(opk))
+    }
+
+    async fn key_log(&self, after: i64, limit: i64) -> Result<Vec<KeyLogRecord>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.key_log.iter().skip(after.max(0) as usize).take(limit as usize).cloned().collect())
+    }
+
+    async fn latest_key(&self, account: &str) -> Result<Option<KeyLogRecord>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.key_log.iter().rev().find(|entry| entry.account == account).cloned())
+    }
+
+    async fn block_user(&self, account: &str, target: &str, timestamp: i64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.blocked.entry((account.to_string(), target.to_string())).or_insert(timestamp);
+        state.requests.remove(&(target.to_string(), account.to_string()));
+        state.chat.retain(|_, (message, _)| message.account != target || message.target != account);
+        Ok(())
+    }
+
+    async fn unblock_user(&self, account: &str, target: &str) -> Result<()> {
+        self.state.lock().unwrap().blocked.remove(&(account.to_string(), target.to_string()));
+        Ok(())
+    }
+
+    async fn is_blocked(&self, account: &str, target: &str) -> Result<bool> {
+        Ok(self.state.lock().unwrap().blocked.contains_key(&(account.to_string(), target.to_string())))
+    }
+
+    async fn list_blocked(&self, account: &str, after: &str, limit: i64) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.blocked.keys()
+            .filter(|(blocker, target)| blocker == account && target.as_str() > after)
+            .take(limit as usize)
+            .map(|(_, target)| target.clone())
+            .collect())
+    }
+
+    async fn take_key_changes(&self, account: &str) -> Result<Vec<KeyChangeRecord>> {
+        let mut state = self.state.lock().unwrap();
+        let (taken, kept) = std::mem::take(&mut state.key_changes).into_iter()
+            .partition(|(owner, _)| owner == account);
+        state.key_changes = kept;
+        Ok(taken.into_iter().map(|(_, change)| change).collect())
+    }
+
+    async fn count_requests(&self, target: &str) -> Result<i64> {
+        let state = self.state.lock().unwrap();
+        Ok(state.requests.keys().filter(|(_, owner)| owner == target).count() as i64)
+    }
+
+    async fn create_request(&self, request: &RequestRecord) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let key = (request.account.clone(), request.target.clone());
+        if state.requests.contains_key(&key) {
+            return Ok(false);
+        }
+
+        state.requests.insert(key, (request.clone(), None));
+        state.contacts.insert((request.account.clone(), request.target.clone()));
+        state.contacts.insert((request.target.clone(), request.account.clone()));
+        Ok(true)
+    }
+
+    async fn list_requests(&self, target: &str, now: i64, after: &str, limit: i64) -> Result<Vec<PendingRequestRecord>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.requests.iter()
+            .filter(|((account, owner), (_, lease))| owner == target && account.as_str() > after && available(*lease, now))
+            .take(limit as usize)
+            .map(|((account, _), (request, _))| PendingRequestRecord { account: account.clone(), timestamp: request.timestamp })
+            .collect())
+    }
+
+    async fn list_outgoing_requests(&self, account: &str, after: &str, limit: i64) -> Result<Vec<PendingRequestRecord>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.requests.iter()
+            .filter(|((owner, target), _)| owner == account && target.as_str() > after)
+            .take(limit as usize)
+            .map(|((_, target), (request, _))| PendingRequestRecord { account: target.clone(), timestamp: request.timestamp })
+            .collect())
+    }
+
+    async fn find_request(&self, account: &str, target: &str) -> Result<Option<RequestRecord>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.requests.get(&(account.to_string(), target.to_string())).map(|(request, _)| request.clone()))
+    }
+
+    async fn lease_request(&self, account: &str, target: &str, until: i64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, lease)) = state.requests.get_mut(&(account.to_string(), target.to_string())) {
+            *lease = Some(until);
+        }
+        Ok(())
+    }
+
+    async fn delete_requests(&self, target: &str, accounts: &[String]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for account in accounts {
+            state.requests.remove(&(account.clone(), target.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn expire_requests(&self, before: i64) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.requests.len();
+        state.requests.retain(|_, (request, _)| request.timestamp >= before);
+        Ok((count - state.requests.len()) as u64)
+    }
+
+    async fn count_messages(&self, target: &str, sender: &str) -> Result<(i64, i64)> {
+        let state = self.state.lock().unwrap();
+        let queued: Vec<&MessageRecord> = state.chat.values()
+            .map(|(message, _)| message)
+            .filter(|message| message.target == target)
+            .collect();
+        let from_sender = queued.iter().filter(|message| message.account == sender).count();
+        Ok((queued.len() as i64, from_sender as i64))
+    }
+
+    async fn insert_message(&self, account: &str, target: &str, message: &str, timestamp: i64, received: i64) -> Result<MessageRecord> {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.sequences.entry((account.to_string(), target.to_string())).or_default();
+        *seq += 1;
+        let seq = *seq;
+        
+        state.next_id += 1;
+        let record = MessageRecord {
+            id: state.next_id,
+            account: account.to_string(),
+            target: target.to_string(),
+            message: message.to_string(),
+            timestamp,
+            received,
+            seq,
+        };
+        state.chat.insert(record.id, (record.clone(), None));
+        Ok(record)
+    }
+
+    async fn lease_messages(&self, account: &str, target: &str, now: i64, until: i64, after: i32, limit: i64) -> Result<Vec<MessageRecord>> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.chat.range_mut(after + 1..)
+            .map(|(_, entry)| entry)
+            .filter(|(message, lease)| message.account == account && message.target == target && available(*lease, now))
+            .take(limit as usize)
+            .map(|(message, lease)| {
+                *lease = Some(until);
+                message.clone()
+            })
+            .collect())
+    }
+
+    async fn pending_messages(&self, target: &str, now: i64, after: i32, limit: i64) -> Result<Vec<MessageRecord>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.chat.range(after + 1..)
+            .map(|(_, entry)| entry)
+            .filter(|(message, lease)| message.target == target && available(*lease, now))
+            .take(limit as usize)
+            .map(|(message, _)| message.clone())
+            .collect())
+    }
+
+    async fn lease_message(&self, id: i32, now: i64, until: i64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        match state.chat.get_mut(&id) {
+            Some((_, lease)) if available(*lease, now) => {
+                *lease = Some(until);
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete_messages(&self, target: &str, ids: &[i32]) -> Result<Vec<String>> {
+        let mut state = self.state.lock().unwrap();
+        let mut senders = vec![];
+        for id in ids {
+            if state.chat.get(id).is_some_and(|(message, _)| message.target == target) {
+                let (message, _) = state.chat.remove(id).unwrap();
+                senders.push(message.account);
+            }
+        }
+        Ok(senders)
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        Ok(())
+    }
+}
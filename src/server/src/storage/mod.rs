@@ -0,0 +1,191 @@
+mod memory;
+mod postgres;
+mod sqlite;
+
+use std::sync::Arc;
+use axum::async_trait;
+use log::info;
+use serde::Serialize;
+
+pub use memory::MemoryStorage;
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+pub type Result<T> = std::result::Result<T, sqlx::Error>;
+
+/// The storage the handlers share, selected by `DATABASE_URL` in [`connect`].
+pub type Db = Arc<dyn Storage>;
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct UserRecord {
+    pub account: String,
+    pub ik_public: String,
+    pub spk_public: String,
+    pub spk_signature: String,
+    pub recovery: Option<String>,
+    pub discoverable: bool,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct OpkRecord {
+    pub id: i32,
+    pub opk: String,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct RequestRecord {
+    pub account: String,
+    pub target: String,
+    pub ek: String,
+    pub ikp: String,
+    pub id: i32,
+    pub timestamp: i64,
+    /// The first message of the session, encrypted under the keys of the request.
+    pub message: Option<String>,
+}
+
+/// The other side of a pending session request and when the request was made.
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
+pub struct PendingRequestRecord {
+    pub account: String,
+    pub timestamp: i64,
+}
+
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
+pub struct MessageRecord {
+    pub id: i32,
+    pub account: String,
+    pub target: String,
+    pub message: String,
+    /// When the sender says it wrote the message.
+    pub timestamp: i64,
+    /// When the server received it.
+    pub received: i64,
+    /// Position of the message among those `account` sent to `target`, so the reader sees them
+    /// numbered without gaps.
+    pub seq: i64,
+}
+
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
+pub struct KeyChangeRecord {
+    pub target: String,
+    pub ik_public: String,
+    pub timestamp: i64,
+}
+
+/// One entry of the key transparency log: `account` registered `ik_public` as its `version`th
+/// identity key, stored as leaf `position` of the tree.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct KeyLogRecord {
+    pub position: i64,
+    pub account: String,
+    pub ik_public: String,
+    pub version: i32,
+}
+
+/// Everything the server persists: accounts with their prekeys, session requests, queued
+/// messages and key change notices. Times are milliseconds for leases and seconds otherwise,
+/// as in the API.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn find_user(&self, account: &str) -> Result<Option<UserRecord>>;
+
+    /// Creates the account with its one-time prekeys and appends its identity key to the key log.
+    async fn create_user(&self, user: &UserRecord, opks: &[OpkRecord]) -> Result<()>;
+
+    /// Replaces the keys and one-time prekeys of an existing account. When the identity key
+    /// changes it is appended to the key log and every contact gets a key change notice stamped
+    /// with `timestamp`.
+    async fn update_user(&self, user: &UserRecord, opks: &[OpkRecord], timestamp: i64) -> Result<()>;
+
+    /// Discoverable accounts whose name starts with `prefix`, other than `exclude`, sorted by
+    /// name after the cursor `after`. `prefix` is matched literally.
+    async fn search_users(&self, prefix: &str, exclude: &str, after: &str, limit: i64) -> Result<Vec<String>>;
+
+    async fn set_discoverable(&self, account: &str, discoverable: bool) -> Result<()>;
+
+    /// Removes and returns the one-time prekey of `account` with the lowest id, recording that
+    /// `claimant` took it at `timestamp`. Concurrent claims never get the same key.
+    async fn claim_opk(&self, account: &str, claimant: &str, timestamp: i64) -> Result<Option<OpkRecord>>;
+
+    /// Key log entries from position `after` on, in order.
+    async fn key_log(&self, after: i64, limit: i64) -> Result<Vec<KeyLogRecord>>;
+
+    /// The newest key log entry of `account`.
+    async fn latest_key(&self, account: &str) -> Result<Option<KeyLogRecord>>;
+
+    /// Adds `target` to the block list of `account` and drops the session request and queued
+    /// messages `target` has pending for `account`.
+    async fn block_user(&self, account: &str, target: &str, timestamp: i64) -> Result<()>;
+
+    async fn unblock_user(&self, account: &str, target: &str) -> Result<()>;
+
+    /// Whether `account` has blocked `target`.
+    async fn is_blocked(&self, account: &str, target: &str) -> Result<bool>;
+
+    /// The accounts `account` has blocked, sorted by name after the cursor `after`.
+    async fn list_blocked(&self, account: &str, after: &str, limit: i64) -> Result<Vec<String>>;
+
+    async fn take_key_changes(&self, account: &str) -> Result<Vec<KeyChangeRecord>>;
+
+    async fn count_requests(&self, target: &str) -> Result<i64>;
+
+    /// Stores a session request and makes both sides contacts. Returns false if `account`
+    /// already has a pending request to `target`.
+    async fn create_request(&self, request: &RequestRecord) -> Result<bool>;
+
+    /// Requesters with a pending request to `target` that is not leased at `now`.
+    async fn list_requests(&self, target: &str, now: i64, after: &str, limit: i64) -> Result<Vec<PendingRequestRecord>>;
+
+    /// Targets of the pending requests `account` has made, leased or not.
+    async fn list_outgoing_requests(&self, account: &str, after: &str, limit: i64) -> Result<Vec<PendingRequestRecord>>;
+
+    async fn find_request(&self, account: &str, target: &str) -> Result<Option<RequestRecord>>;
+
+    async fn lease_request(&self, account: &str, target: &str, until: i64) -> Result<()>;
+
+    async fn delete_requests(&self, target: &str, accounts: &[String]) -> Result<()>;
+
+    /// Deletes every request made before `before` and returns how many there were.
+    async fn expire_requests(&self, before: i64) -> Result<u64>;
+
+    /// Returns the number of messages queued for `target` in total and from `sender`.
+    async fn count_messages(&self, target: &str, sender: &str) -> Result<(i64, i64)>;
+
+    /// Queues a message received at `received`, numbering it after the last one between the two
+    /// accounts.
+    async fn insert_message(&self, account: &str, target: &str, message: &str, timestamp: i64, received: i64) -> Result<MessageRecord>;
+
+    /// Leases up to `limit` messages from `account` to `target` after the cursor `after` that are
+    /// not leased at `now`, in id order.
+    async fn lease_messages(&self, account: &str, target: &str, now: i64, until: i64, after: i32, limit: i64) -> Result<Vec<MessageRecord>>;
+
+    /// Messages for `target` from anyone that are not leased at `now`, in id order.
+    async fn pending_messages(&self, target: &str, now: i64, after: i32, limit: i64) -> Result<Vec<MessageRecord>>;
+
+    /// Leases a single message. Returns false if it is gone or leased by someone else.
+    async fn lease_message(&self, id: i32, now: i64, until: i64) -> Result<bool>;
+
+    /// Deletes the messages for `target` among `ids` and returns the sender of each one.
+    async fn delete_messages(&self, target: &str, ids: &[i32]) -> Result<Vec<String>>;
+
+    /// Brings the schema up to date. The in-memory storage has nothing to do.
+    async fn migrate(&self) -> Result<()>;
+}
+
+/// Opens the storage named by `url`: `postgres://…`, `sqlite://…` or `memory`.
+pub async fn connect(url: &str) -> std::result::Result<Db, Box<dyn std::error::Error>> {
+    let db: Db = if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Arc::new(PostgresStorage::connect(url).await?)
+    } else if url.starts_with("sqlite:") {
+        Arc::new(SqliteStorage::connect(url).await?)
+    } else if url == "memory" || url == "memory://" {
+        Arc::new(MemoryStorage::default())
+    } else {
+        return Err(format!("Unsupported DATABASE_URL {:?}", url).into());
+    };
+
+    db.migrate().await?;
+    info!("Database schema is up to date");
+    Ok(db)
+}
@@ -0,0 +1,318 @@
+use axum::async_trait;
+use sqlx::{PgPool, Postgres, Row, Transaction, postgres::PgPoolOptions};
+use super::{KeyChangeRecord, KeyLogRecord, MessageRecord, OpkRecord, PendingRequestRecord, RequestRecord, Result, Storage, UserRecord};
+
+/// Escapes the `LIKE` wildcards in `prefix` and appends one for the rest of the name, for use
+/// with `ESCAPE '\'`.
+fn like_prefix(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '\\' | '%' | '_') { pattern.push('\\'); }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(url)
+            .await?;
+        Ok(Self { pool })
+    }
+}
+
+/// Appends the current identity key of `account` to the key log. The table lock keeps positions
+/// contiguous when two accounts register at once.
+async fn append_key_log(tx: &mut Transaction<'_, Postgres>, account: &str, ik_public: &str) -> Result<()> {
+    sqlx::query("LOCK TABLE key_log IN EXCLUSIVE MODE").execute(&mut **tx).await?;
+    sqlx::query(
+        "INSERT INTO key_log (position, account, ik_public, version)
+        SELECT (SELECT COALESCE(MAX(position) + 1, 0) FROM key_log), $1, $2,
+            (SELECT COALESCE(MAX(version), 0) + 1 FROM key_log WHERE account = $1)"
+    )
+        .bind(account).bind(ik_public)
+        .execute(&mut **tx).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn find_user(&self, account: &str) -> Result<Option<UserRecord>> {
+        sqlx::query_as("SELECT account, ik_public, spk_public, spk_signature, recovery, discoverable FROM \"user\" WHERE account = $1")
+            .bind(account)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn create_user(&self, user: &UserRecord, opks: &[OpkRecord]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO \"user\" (account, ik_public, spk_public, spk_signature, recovery, discoverable) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(&user.account).bind(&user.ik_public).bind(&user.spk_public).bind(&user.spk_signature).bind(&user.recovery)
+            .bind(user.discoverable)
+            .execute(&mut *tx).await?;
+        for key in opks {
+            sqlx::query("INSERT INTO opk (account, opk, id) VALUES ($1, $2, $3)")
+                .bind(&user.account).bind(&key.opk).bind(key.id)
+                .execute(&mut *tx).await?;
+        }
+        append_key_log(&mut tx, &user.account, &user.ik_public).await?;
+        tx.commit().await
+    }
+
+    async fn update_user(&self, user: &UserRecord, opks: &[OpkRecord], timestamp: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let previous: String = sqlx::query_scalar("SELECT ik_public FROM \"user\" WHERE account = $1 FOR UPDATE")
+            .bind(&user.account)
+            .fetch_one(&mut *tx).await?;
+
+        sqlx::query("UPDATE \"user\" SET ik_public = $1, spk_public = $2, spk_signature = $3, recovery = $4, discoverable = $5 WHERE account = $6")
+            .bind(&user.ik_public).bind(&user.spk_public).bind(&user.spk_signature).bind(&user.recovery).bind(user.discoverable)
+            .bind(&user.account)
+            .execute(&mut *tx).await?;
+        sqlx::query("DELETE FROM opk WHERE account = $1").bind(&user.account).execute(&mut *tx).await?;
+        for key in opks {
+            sqlx::query("INSERT INTO opk (account, opk, id) VALUES ($1, $2, $3)")
+                .bind(&user.account).bind(&key.opk).bind(key.id)
+                .execute(&mut *tx).await?;
+        }
+
+        if previous.trim() != user.ik_public {
+            append_key_log(&mut tx, &user.account, &user.ik_public).await?;
+            sqlx::query("INSERT INTO key_change (account, target, ik_public, timestamp) SELECT target, account, $2, $3 FROM contact WHERE account = $1")
+                .bind(&user.account).bind(&user.ik_public).bind(timestamp)
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await
+    }
+
+    async fn search_users(&self, prefix: &str, exclude: &str, after: &str, limit: i64) -> Result<Vec<String>> {
+        sqlx::query_scalar("SELECT account FROM \"user\" WHERE discoverable and account LIKE $1 ESCAPE '\\' and account != $2 and account > $3 ORDER BY account LIMIT $4")
+            .bind(like_prefix(prefix)).bind(exclude).bind(after).bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn set_discoverable(&self, account: &str, discoverable: bool) -> Result<()> {
+        sqlx::query("UPDATE \"user\" SET discoverable = $1 WHERE account = $2")
+            .bind(discoverable).bind(account)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn claim_opk(&self, account: &str, claimant: &str, timestamp: i64) -> Result<Option<OpkRecord>> {
+        let mut tx = self.pool.begin().await?;
+        // A row locked by a concurrent claim is skipped rather than waited for and handed out twice.
+        let opk: Option<OpkRecord> = sqlx::query_as(
+            "DELETE FROM opk WHERE account = $1 and id = (
+                SELECT id FROM opk WHERE account = $1 ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED
+            ) RETURNING id, opk"
+        )
+            .bind(account)
+            .fetch_optional(&mut *tx).await?;
+
+        if let Some(opk) = &opk {
+            sqlx::query("INSERT INTO opk_claim (account, opk_id, claimant, timestamp) VALUES ($1, $2, $3, $4)")
+                .bind(account).bind(opk.id).bind(claimant).bind(timestamp)
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(opk)
+    }
+
+    async fn key_log(&self, after: i64, limit: i64) -> Result<Vec<KeyLogRecord>> {
+        sqlx::query_as("SELECT position, account, ik_public, version FROM key_log WHERE position >= $1 ORDER BY position LIMIT $2")
+            .bind(after).bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn latest_key(&self, account: &str) -> Result<Option<KeyLogRecord>> {
+        sqlx::query_as("SELECT position, account, ik_public, version FROM key_log WHERE account = $1 ORDER BY version DESC LIMIT 1")
+            .bind(account)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn block_user(&self, account: &str, target: &str, timestamp: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO blocked (account, target, timestamp) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING")
+            .bind(account).bind(target).bind(timestamp)
+            .execute(&mut *tx).await?;
+        sqlx::query("DELETE FROM request WHERE account = $1 and target = $2")
+            .bind(target).bind(account)
+            .execute(&mut *tx).await?;
+        sqlx::query("DELETE FROM chat WHERE account = $1 and target = $2")
+            .bind(target).bind(account)
+            .execute(&mut *tx).await?;
+        tx.commit().await
+    }
+
+    async fn unblock_user(&self, account: &str, target: &str) -> Result<()> {
+        sqlx::query("DELETE FROM blocked WHERE account = $1 and target = $2")
+            .bind(account).bind(target)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn is_blocked(&self, account: &str, target: &str) -> Result<bool> {
+        let row: Option<i64> = sqlx::query_scalar("SELECT timestamp FROM blocked WHERE account = $1 and target = $2")
+            .bind(account).bind(target)
+            .fetch_optional(&self.pool).await?;
+        Ok(row.is_some())
+    }
+
+    async fn list_blocked(&self, account: &str, after: &str, limit: i64) -> Result<Vec<String>> {
+        sqlx::query_scalar("SELECT target FROM blocked WHERE account = $1 and target > $2 ORDER BY target LIMIT $3")
+            .bind(account).bind(after).bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn take_key_changes(&self, account: &str) -> Result<Vec<KeyChangeRecord>> {
+        sqlx::query_as("DELETE FROM key_change WHERE account = $1 RETURNING target, ik_public, timestamp")
+            .bind(account)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn count_requests(&self, target: &str) -> Result<i64> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM request WHERE target = $1")
+            .bind(target)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn create_request(&self, request: &RequestRecord) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let inserted = sqlx::query("INSERT INTO request (account, target, ek, ikp, id, timestamp, message) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT DO NOTHING")
+            .bind(&request.account).bind(&request.target).bind(&request.ek).bind(&request.ikp).bind(request.id)
+            .bind(request.timestamp).bind(&request.message)
+            .execute(&mut *tx).await?;
+        if inserted.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("INSERT INTO contact (account, target) VALUES ($1, $2), ($2, $1) ON CONFLICT DO NOTHING")
+            .bind(&request.account).bind(&request.target)
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn list_requests(&self, target: &str, now: i64, after: &str, limit: i64) -> Result<Vec<PendingRequestRecord>> {
+        sqlx::query_as("SELECT account, timestamp FROM request WHERE target = $1 and (leased_until IS NULL or leased_until < $2) and account > $3 ORDER BY account LIMIT $4")
+            .bind(target).bind(now).bind(after).bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn list_outgoing_requests(&self, account: &str, after: &str, limit: i64) -> Result<Vec<PendingRequestRecord>> {
+        sqlx::query_as("SELECT target AS account, timestamp FROM request WHERE account = $1 and target > $2 ORDER BY target LIMIT $3")
+            .bind(account).bind(after).bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn find_request(&self, account: &str, target: &str) -> Result<Option<RequestRecord>> {
+        sqlx::query_as("SELECT account, target, ek, ikp, id, timestamp, message FROM request WHERE account = $1 and target = $2")
+            .bind(account).bind(target)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn lease_request(&self, account: &str, target: &str, until: i64) -> Result<()> {
+        sqlx::query("UPDATE request SET leased_until = $1 WHERE account = $2 and target = $3")
+            .bind(until).bind(account).bind(target)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn delete_requests(&self, target: &str, accounts: &[String]) -> Result<()> {
+        sqlx::query("DELETE FROM request WHERE target = $1 and account = ANY($2)")
+            .bind(target).bind(accounts)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn expire_requests(&self, before: i64) -> Result<u64> {
+        let deleted = sqlx::query("DELETE FROM request WHERE timestamp < $1")
+            .bind(before)
+            .execute(&self.pool).await?;
+        Ok(deleted.rows_affected())
+    }
+
+    async fn count_messages(&self, target: &str, sender: &str) -> Result<(i64, i64)> {
+        let row = sqlx::query("SELECT COUNT(*) AS total, COUNT(*) FILTER (WHERE account = $2) AS sender FROM chat WHERE target = $1")
+            .bind(target).bind(sender)
+            .fetch_one(&self.pool).await?;
+        Ok((row.try_get("total")?, row.try_get("sender")?))
+    }
+
+    async fn insert_message(&self, account: &str, target: &str, message: &str, timestamp: i64, received: i64) -> Result<MessageRecord> {
+        let mut tx = self.pool.begin().await?;
+        let seq: i64 = sqlx::query_scalar(
+            "INSERT INTO conversation (account, target, seq) VALUES ($1, $2, 1)
+            ON CONFLICT (account, target) DO UPDATE SET seq = conversation.seq + 1 RETURNING seq"
+        )
+            .bind(account).bind(target)
+            .fetch_one(&mut *tx).await?;
+        let record = sqlx::query_as(
+            "INSERT INTO chat (account, target, message, timestamp, received, seq) VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, account, target, message, timestamp, received, seq"
+        )
+            .bind(account).bind(target).bind(message).bind(timestamp).bind(received).bind(seq)
+            .fetch_one(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(record)
+    }
+
+    async fn lease_messages(&self, account: &str, target: &str, now: i64, until: i64, after: i32, limit: i64) -> Result<Vec<MessageRecord>> {
+        let mut messages: Vec<MessageRecord> = sqlx::query_as(
+            "UPDATE chat SET leased_until = $3 WHERE id IN (
+                SELECT id FROM chat WHERE account = $1 and target = $2 and (leased_until IS NULL or leased_until < $4) and id > $5
+                ORDER BY id LIMIT $6 FOR UPDATE SKIP LOCKED
+            ) RETURNING id, account, target, message, timestamp, received, seq"
+        )
+            .bind(account).bind(target).bind(until).bind(now).bind(after).bind(limit)
+            .fetch_all(&self.pool).await?;
+
+        messages.sort_by_key(|message| message.id);
+        Ok(messages)
+    }
+
+    async fn pending_messages(&self, target: &str, now: i64, after: i32, limit: i64) -> Result<Vec<MessageRecord>> {
+        sqlx::query_as(
+            "SELECT id, account, target, message, timestamp, received, seq FROM chat
+            WHERE target = $1 and (leased_until IS NULL or leased_until < $2) and id > $3 ORDER BY id LIMIT $4"
+        )
+            .bind(target).bind(now).bind(after).bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn lease_message(&self, id: i32, now: i64, until: i64) -> Result<bool> {
+        let leased = sqlx::query("UPDATE chat SET leased_until = $2 WHERE id = $1 and (leased_until IS NULL or leased_until < $3)")
+            .bind(id).bind(until).bind(now)
+            .execute(&self.pool).await?;
+        Ok(leased.rows_affected() == 1)
+    }
+
+    async fn delete_messages(&self, target: &str, ids: &[i32]) -> Result<Vec<String>> {
+        sqlx::query_scalar("DELETE FROM chat WHERE target = $1 and id = ANY($2) RETURNING account")
+            .bind(target).bind(ids)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        sqlx::migrate!("./migrations/postgres").run(&self.pool).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use axum::{Extension, Json};
+use chrono::Local;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use crate::auth::{AuthState, AuthUser};
+use crate::error::ServerError;
+use crate::push::PushHub;
+use crate::storage::{Db, MemoryStorage};
+use crate::transparency::KeyLog;
+use crate::{ack, block, create, create_message, get_message, session, LEASE_MILLIS, MAX_QUEUED_MESSAGES};
+
+fn db() -> Db {
+    Arc::new(MemoryStorage::default())
+}
+
+fn hub() -> Extension<Arc<PushHub>> {
+    Extension(Arc::new(PushHub::default()))
+}
+
+/// A key log with its own signing key, since the tests run in parallel.
+fn key_log() -> Extension<Arc<KeyLog>> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let folder = std::env::temp_dir().join(format!("e2ee-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    std::fs::create_dir_all(&folder).unwrap();
+    let log = KeyLog::load(&folder.join("transparency.key")).unwrap();
+    std::fs::remove_dir_all(&folder).unwrap();
+    Extension(Arc::new(log))
+}
+
+fn payload<T: DeserializeOwned>(value: Value) -> Json<T> {
+    Json(serde_json::from_value(value).unwrap())
+}
+
+fn as_user(account: &str) -> AuthUser {
+    AuthUser(account.to_string())
+}
+
+/// Registers `account` with `opks` one-time prekeys numbered from 1.
+async fn register(db: &Db, account: &str, opks: i32) {
+    let key = hex::encode([7u8; 32]);
+    let opk: Vec<Value> = (1..=opks).map(|id| json!({ "id": id, "key": key })).collect();
+    create(Extension(db.clone()), Extension(Arc::new(AuthState::default())), payload(json!({
+        "account": account,
+        "ik_public": key,
+        "spk_public": key,
+        "spk_signature": hex::encode([7u8; 64]),
+        "opk": opk,
+    }))).await.unwrap();
+}
+
+async fn send(db: &Db, from: &str, to: &str, count: usize) {
+    for i in 0..count {
+        create_message(Extension(db.clone()), hub(), as_user(from), payload(json!({
+            "target": to,
+            "message": format!("message {}", i),
+            "timestamp": Local::now().timestamp(),
+        }))).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn session_claims_each_prekey_once() {
+    let db = db();
+    let log = key_log();
+    register(&db, "alice", 2).await;
+    register(&db, "bob", 0).await;
+    register(&db, "carol", 0).await;
+
+    let first = session(Extension(db.clone()), log.clone(), as_user("bob"), payload(json!({ "target": "alice" }))).await.unwrap();
+    let second = session(Extension(db.clone()), log.clone(), as_user("carol"), payload(json!({ "target": "alice" }))).await.unwrap();
+    assert_ne!(first.id, second.id);
+
+    let third = session(Extension(db.clone()), log.clone(), as_user("bob"), payload(json!({ "target": "alice" }))).await;
+    assert!(matches!(third, Err(ServerError::NoPrekeys(target)) if target == "alice"));
+}
+
+#[tokio::test]
+async fn messages_are_paged_by_cursor() {
+    let db = db();
+    register(&db, "alice", 0).await;
+    register(&db, "bob", 0).await;
+    send(&db, "alice", "bob", 5).await;
+
+    let mut ids = vec![];
+    let mut after: Option<i32> = None;
+    let mut pages = 0;
+    loop {
+        let page = get_message(Extension(db.clone()), as_user("bob"), payload(json!({ "target": "alice", "after": after, "limit": 2 })))
+            .await.unwrap();
+        pages += 1;
+        ids.extend(page.items.iter().map(|message| message.id));
+        match page.next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+
+    assert_eq!(pages, 3);
+    assert_eq!(ids.len(), 5);
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[tokio::test]
+async fn unacknowledged_messages_return_after_the_lease() {
+    let db = db();
+    register(&db, "alice", 0).await;
+    register(&db, "bob", 0).await;
+    send(&db, "alice", "bob", 3).await;
+
+    let fetch = || get_message(Extension(db.clone()), as_user("bob"), payload(json!({ "target": "alice" })));
+    let Json(leased) = fetch().await.unwrap();
+    let leased = leased.items;
+    assert_eq!(leased.len(), 3);
+    assert!(fetch().await.unwrap().items.is_empty(), "leased messages are not delivered twice");
+
+    let acked = leased[0].id;
+    ack(Extension(db.clone()), hub(), as_user("bob"), payload(json!({ "messages": [acked] }))).await.unwrap();
+
+    let expired = Local::now().timestamp_millis() + LEASE_MILLIS + 1;
+    let again = db.lease_messages("alice", "bob", expired, expired + LEASE_MILLIS, 0, 10).await.unwrap();
+    let ids: Vec<i32> = again.iter().map(|message| message.id).collect();
+    assert_eq!(ids, leased[1..].iter().map(|message| message.id).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn ack_only_removes_messages_for_the_caller() {
+    let db = db();
+    register(&db, "alice", 0).await;
+    register(&db, "bob", 0).await;
+    register(&db, "mallory", 0).await;
+    send(&db, "alice", "bob", 1).await;
+
+    let id = db.pending_messages("bob", 0, 0, 10).await.unwrap()[0].id;
+    ack(Extension(db.clone()), hub(), as_user("mallory"), payload(json!({ "messages": [id] }))).await.unwrap();
+    assert_eq!(db.pending_messages("bob", 0, 0, 10).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn blocked_senders_cannot_see_a_full_queue() {
+    let db = db();
+    for account in ["alice", "bob", "mallory"] {
+        register(&db, account, 0).await;
+    }
+    block(Extension(db.clone()), as_user("bob"), payload(json!({ "target": "mallory" }))).await.unwrap();
+    for i in 0..MAX_QUEUED_MESSAGES {
+        db.insert_message(&format!("sender{}", i % 10), "bob", "queued", 1, 1).await.unwrap();
+    }
+
+    let message = || payload(json!({ "target": "bob", "message": "hello", "timestamp": Local::now().timestamp() }));
+    let full = create_message(Extension(db.clone()), hub(), as_user("alice"), message()).await;
+    assert!(matches!(full, Err(ServerError::QueueFull(_))));
+    let blocked = create_message(Extension(db.clone()), hub(), as_user("mallory"), message()).await;
+    assert!(blocked.is_ok(), "a blocked sender is answered as if the message was queued");
+}
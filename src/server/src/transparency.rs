@@ -0,0 +1,260 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use axum::{Extension, Json};
+use chrono::Local;
+use ed25519_dalek::{Signer, SigningKey};
+use log::info;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::error::ServerError;
+use crate::storage::{Db, KeyLogRecord};
+
+const TREE_HEAD_CONTEXT: &str = "e2ee-tree-head";
+const DEFAULT_KEY_PATH: &str = "transparency.key";
+const SYNC_PAGE_SIZE: i64 = 1000;
+
+type Hash = [u8; 32];
+
+/// The leaf of the binding of `account` to its `version`th identity key, hashed as in RFC 6962.
+fn leaf_hash(account: &str, ik_public: &str, version: i32) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update(format!("{}:{}:{}", account, ik_public, version).as_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The largest power of two smaller than `n`, where the tree of `n` leaves splits.
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n { k *= 2; }
+    k
+}
+
+/// The leaf hashes of the log and the roots of every complete subtree above them, so a root or a
+/// proof takes O(log n) cached hashes instead of rehashing the whole log.
+#[derive(Default)]
+struct Tree {
+    /// `levels[0]` holds the leaves, `levels[l][i]` the root of leaves `i * 2^l` to `(i + 1) * 2^l`.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl Tree {
+    fn len(&self) -> usize {
+        self.levels.first().map_or(0, Vec::len)
+    }
+
+    fn push(&mut self, leaf: Hash) {
+        let mut hash = leaf;
+        for level in 0.. {
+            if self.levels.len() == level { self.levels.push(vec![]); }
+            let nodes = &mut self.levels[level];
+            nodes.push(hash);
+            if nodes.len() % 2 == 1 { return; }
+            hash = node_hash(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+        }
+    }
+
+    /// The root of the `size` leaves from `start`. Every range the RFC 6962 split reaches starts
+    /// at a multiple of its largest power of two, so complete ranges are read from the cache.
+    fn root(&self, start: usize, size: usize) -> Hash {
+        match size {
+            0 => Sha256::digest([]).into(),
+            n if n.is_power_of_two() => self.levels[n.trailing_zeros() as usize][start / n],
+            n => {
+                let k = split(n);
+                node_hash(&self.root(start, k), &self.root(start + k, n - k))
+            }
+        }
+    }
+
+    /// The audit path of leaf `index` in the `size` leaves from `start`, from the bottom up.
+    fn inclusion_path(&self, index: usize, start: usize, size: usize) -> Vec<Hash> {
+        if size <= 1 { return vec![]; }
+
+        let k = split(size);
+        if index < k {
+            let mut path = self.inclusion_path(index, start, k);
+            path.push(self.root(start + k, size - k));
+            path
+        } else {
+            let mut path = self.inclusion_path(index - k, start + k, size - k);
+            path.push(self.root(start, k));
+            path
+        }
+    }
+
+    /// The proof that the tree of the first `from` of the `size` leaves from `start` is a prefix
+    /// of the tree of all of them.
+    fn consistency_path(&self, from: usize, start: usize, size: usize, complete: bool) -> Vec<Hash> {
+        if from == size {
+            return if complete { vec![] } else { vec![self.root(start, size)] };
+        }
+
+        let k = split(size);
+        if from <= k {
+            let mut path = self.consistency_path(from, start, k, complete);
+            path.push(self.root(start + k, size - k));
+            path
+        } else {
+            let mut path = self.consistency_path(from - k, start + k, size - k, false);
+            path.push(self.root(start, k));
+            path
+        }
+    }
+}
+
+/// The root of the log at `size` entries, signed by the server. `key` is the public half of the
+/// signing key, which clients pin the first time they see it.
+#[derive(Serialize, Clone, Debug)]
+pub struct TreeHead {
+    size: i64,
+    root: String,
+    timestamp: i64,
+    signature: String,
+    key: String,
+}
+
+/// Shows that an identity key is the `version`th key of its account and sits at `index` in the
+/// tree summarized by `head`.
+#[derive(Serialize, Debug)]
+pub struct KeyProof {
+    index: i64,
+    version: i32,
+    proof: Vec<String>,
+    head: TreeHead,
+}
+
+/// The key transparency log. Entries live in the storage, the tree over them is cached here and
+/// caught up before every proof.
+pub struct KeyLog {
+    signing_key: SigningKey,
+    leaves: Mutex<Tree>,
+}
+
+/// Where the signing key is kept, `TRANSPARENCY_KEY_PATH` or `transparency.key` by default.
+pub fn key_path() -> PathBuf {
+    PathBuf::from(std::env::var("TRANSPARENCY_KEY_PATH").unwrap_or(DEFAULT_KEY_PATH.to_string()))
+}
+
+impl KeyLog {
+    /// Loads the signing key from `path`, creating it on first start. Clients reject tree heads
+    /// once the key changes, so the file has to be kept.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let seed: [u8; 32] = match std::fs::read_to_string(path) {
+            Ok(seed) => <[u8; 32]>::try_from(hex::decode(seed.trim())?)
+                .map_err(|_| format!("{} does not hold a 32 byte key", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut seed = [0u8; 32];
+                OsRng.fill_bytes(&mut seed);
+                // Whoever reads the key can sign tree heads, so only the owner may.
+                OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?
+                    .write_all(hex::encode(seed).as_bytes())?;
+                info!("[Log] Created a new tree head signing key in {}", path.display());
+                seed
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { signing_key: SigningKey::from_bytes(&seed), leaves: Mutex::new(Tree::default()) })
+    }
+
+    async fn sync(&self, db: &Db) -> Result<(), ServerError> {
+        loop {
+            let after = self.leaves.lock().unwrap().len() as i64;
+            let entries = db.key_log(after, SYNC_PAGE_SIZE).await?;
+            let full = entries.len() as i64 == SYNC_PAGE_SIZE;
+
+            let mut leaves = self.leaves.lock().unwrap();
+            for entry in entries {
+                // Another request may have caught up in the meantime.
+                if entry.position == leaves.len() as i64 {
+                    leaves.push(leaf_hash(&entry.account, &entry.ik_public, entry.version));
+                }
+            }
+            if !full { return Ok(()); }
+        }
+    }
+
+    fn head(&self, leaves: &Tree) -> TreeHead {
+        let size = leaves.len() as i64;
+        let root = hex::encode(leaves.root(0, leaves.len()));
+        let timestamp = Local::now().timestamp();
+        let signature = self.signing_key.sign(format!("{}:{}:{}:{}", TREE_HEAD_CONTEXT, size, root, timestamp).as_bytes());
+
+        TreeHead {
+            size,
+            root,
+            timestamp,
+            signature: hex::encode(signature.to_bytes()),
+            key: hex::encode(self.signing_key.verifying_key().to_bytes()),
+        }
+    }
+
+    pub async fn prove(&self, db: &Db, entry: &KeyLogRecord) -> Result<KeyProof, ServerError> {
+        self.sync(db).await?;
+        let leaves = self.leaves.lock().unwrap();
+
+        Ok(KeyProof {
+            index: entry.position,
+            version: entry.version,
+            proof: leaves.inclusion_path(entry.position as usize, 0, leaves.len()).iter().map(hex::encode).collect(),
+            head: self.head(&leaves),
+        })
+    }
+}
+
+#[axum::debug_handler]
+pub async fn head(
+    Extension(db): Extension<Db>,
+    Extension(log): Extension<Arc<KeyLog>>,
+) -> Result<Json<TreeHead>, ServerError> {
+    log.sync(&db).await?;
+    let leaves = log.leaves.lock().unwrap();
+    Ok(Json(log.head(&leaves)))
+}
+
+#[derive(Deserialize)]
+pub struct ConsistencyPayload {
+    from: i64,
+    to: i64,
+}
+
+#[derive(Serialize)]
+pub struct ConsistencyResponse {
+    proof: Vec<String>,
+}
+
+/// Proves that the tree of `to` entries extends the tree of `from` entries.
+#[axum::debug_handler]
+pub async fn consistency(
+    Extension(db): Extension<Db>,
+    Extension(log): Extension<Arc<KeyLog>>,
+    Json(payload): Json<ConsistencyPayload>
+) -> Result<Json<ConsistencyResponse>, ServerError> {
+    log.sync(&db).await?;
+    let leaves = log.leaves.lock().unwrap();
+
+    if payload.from < 0 || payload.from > payload.to || payload.to > leaves.len() as i64 {
+        return Err(ServerError::InvalidRequest(format!("There is no consistency proof from {} to {}", payload.from, payload.to)));
+    }
+    let proof = if payload.from == 0 {
+        vec![]
+    } else {
+        leaves.consistency_path(payload.from as usize, 0, payload.to as usize, true)
+    };
+    Ok(Json(ConsistencyResponse { proof: proof.iter().map(hex::encode).collect() }))
+}